@@ -1,19 +1,73 @@
+#![allow(non_snake_case)] // crate name must match the dfx canister name
+
 use candid::{CandidType, Decode, Deserialize, Encode};
-use ic_cdk::{caller, query, update};
+use ic_cdk::{
+    api::{call::CallResult, management_canister::main::raw_rand, time},
+    caller, init, post_upgrade, query, update,
+};
+use ic_cdk_timers::TimerId;
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
-    {BoundedStorable, DefaultMemoryImpl, StableBTreeMap, Storable},
+    {BoundedStorable, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable},
 };
-use std::{borrow::Cow, cell::RefCell};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell, collections::HashMap, ops::Bound, time::Duration};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 const MAX_VALUE_SIZE: u32 = 5000;
+const MAX_BALLOT_SIZE: u32 = 1000;
+const MAX_ROUNDS_SIZE: u32 = 8192;
+const MAX_BALLOT_HISTORY_SIZE: u32 = 12_000;
+const MAX_BALLOT_CHANGES: usize = 10;
+const MAX_COMMITMENT_SIZE: u32 = 100;
+const COMMITMENT_LEN: usize = 32;
+const MAX_REASON_LEN: usize = 500;
+const MAX_DESCRIPTION_LEN: usize = 1000;
+const MAX_BALLOT_PAGE: u32 = 100;
+const MAX_MEMBER_SIZE: u32 = 100;
+const MAX_MEMBER_PAGE: u32 = 100;
+const MAX_PENDING_TALLY_SIZE: u32 = MAX_ROUNDS_SIZE + 100;
+const MAX_PENDING_SNAPSHOT_SIZE: u32 = 100;
+// Members copied per message into the electorate of a new proposal.
+const SNAPSHOT_BATCH_SIZE: usize = 500;
+// Electorate entries and delegation hops, or ballots, handled per message
+// while a proposal is tallying.
+const TALLY_BATCH_SIZE: usize = 500;
+const MAX_DEADLINE_EXTENSIONS: u64 = 50;
+// Room kept free in a new proposal for what it gains later: a history entry
+// of 25 bytes per deadline extension and of 10 bytes per status change, and
+// the deadlines, electorate, conviction and outcome set along the way.
+const PROPOSAL_GROWTH: usize = MAX_DEADLINE_EXTENSIONS as usize * 25 + 8 * 10 + 64;
+const MAX_OPTIONS: usize = 16;
+const MAX_OPTION_LABEL_LEN: usize = 100;
+const MAX_STAR_SCORE: u8 = 5;
+const MAX_JURY_SIZE: u32 = 30;
+// Reason recorded on ballots migrated from the first version of the
+// canister, which did not keep the voters' choices.
+const LEGACY_BALLOT_REASON: &str = "Cast before choices were recorded";
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 enum Choice {
     Approve,
     Reject,
+    /// Abstain. Valid on every kind of proposal.
     Pass,
+    /// Index of the selected option on a multi-option proposal.
+    Option(u32),
+    /// Option indices from most to least preferred on a ranked-choice
+    /// proposal. Options left out are never counted for this ballot.
+    Ranking(Vec<u32>),
+    /// The approved options on an approval proposal, in any order.
+    Approvals(Vec<u32>),
+    /// A score from 0 to 5 for every option of a STAR proposal, in option
+    /// order.
+    Scores(Vec<u8>),
+    /// The number of votes for every option of a quadratic proposal, in
+    /// option order.
+    Votes(Vec<u32>),
+    /// Support staked on a conviction proposal, out of the voter's weight
+    /// not staked on other open conviction proposals.
+    Stake(u64),
 }
 
 #[derive(CandidType)]
@@ -22,11 +76,95 @@ enum VoteError {
     ProposalIsNotActive,
     NoSuchProposal,
     AccessRejected,
+    ProposalAlreadyExists,
+    ReasonTooLong,
+    DescriptionTooLong,
+    ProposalTooLarge,
+    VotingPeriodEnded,
+    InvalidVotingPeriod,
+    InvalidRules,
+    InvalidWeight,
+    InvalidOptions,
+    InvalidChoice,
+    InsufficientCredits { cost: u64, remaining: u64 },
+    InsufficientStake { requested: u64, available: u64 },
+    NoVotingPower,
+    LedgerCallFailed(String),
+    NotTokenWeighted,
+    TokensLocked,
+    NotAMember,
+    AlreadyAMember,
+    ProposalNotEditable,
+    InvalidJurySize,
+    NotAJuror,
+    RandomnessUnavailable(String),
+    CommitmentRequired,
+    NotASecretBallot,
+    InvalidCommitment,
+    NotCommitted,
+    CommitmentMismatch,
+    NotVoted,
+    VoteChangesDisabled,
+    TooManyBallotChanges,
+    InvalidDelegate,
+    DelegationCycle,
+    NotDelegating,
+    SnapshotInProgress,
+    InvalidTransition { from: ProposalStatus, to: ProposalStatus },
     UpdateError(String), // Improved error message
 }
 
 #[derive(CandidType, Deserialize)]
 struct Proposal {
+    description: String,
+    kind: ProposalKind,
+    topic: Topic,
+    /// Whether voters may change or retract their ballot while the proposal
+    /// is open.
+    allow_vote_changes: bool,
+    /// Number of ballots per choice.
+    headcount: Tally,
+    /// Voting weight per choice; this is what decides the outcome.
+    weighted: Tally,
+    /// Tallies of each option of a multi-option proposal, in option order.
+    /// Ranked-choice proposals count first preferences here, approval
+    /// proposals approvals, STAR proposals weighted score totals and quadratic
+    /// proposals weighted votes.
+    option_tallies: Vec<OptionTally>,
+    status: ProposalStatus,
+    owner: candid::Principal,
+    created_at: u64,
+    voting_period: Option<VotingPeriod>,
+    voting_ends_at: Option<u64>,
+    rules: DecisionRules,
+    /// Snapshot of the eligible voters, taken at creation when voting is
+    /// restricted to members or to a jury. `None` means anyone may vote.
+    electorate: Option<Electorate>,
+    /// ICRC-2 ledger whose tokens give the voting power, if any. Voters
+    /// vote with the tokens they escrowed on the proposal.
+    ledger: Option<candid::Principal>,
+    wait_for_quiet: Option<WaitForQuiet>,
+    /// Total time the deadline has been pushed back by wait-for-quiet.
+    deadline_extended_by: u64,
+    secret_ballot: Option<SecretBallot>,
+    /// End of the reveal window, set when voting on a secret-ballot proposal
+    /// ends.
+    reveal_ends_at: Option<u64>,
+    outcome: Option<Outcome>,
+    history: Vec<HistoryEntry>,
+    /// Whether the tallies are withheld until the proposal closes.
+    blind_results: bool,
+    /// Conviction of a conviction-voting proposal, from the first stake on.
+    conviction: Option<Conviction>,
+    /// Members drawn to vote on the proposal, if it is decided by a jury.
+    jury: Option<Jury>,
+}
+
+/// Proposal record of the first version of the canister, which counted
+/// every ballot as one vote and kept the voters in the record. Such records
+/// are converted by `migrate_legacy_proposals`.
+#[derive(CandidType, Deserialize)]
+struct LegacyProposal {
     description: String,
     approve: u32,
     reject: u32,
@@ -36,14 +174,530 @@ struct Proposal {
     owner: candid::Principal,
 }
 
+/// Proposal record left undecoded, so records of any version can be read.
+struct RawProposal(Vec<u8>);
+
+/// Public view of a proposal returned by `get_proposal`. The tallies are
+/// `None` while they are withheld.
+#[derive(CandidType)]
+struct ProposalView {
+    description: String,
+    kind: ProposalKind,
+    topic: Topic,
+    allow_vote_changes: bool,
+    blind_results: bool,
+    headcount: Option<Tally>,
+    weighted: Option<Tally>,
+    option_tallies: Option<Vec<OptionTally>>,
+    status: ProposalStatus,
+    owner: candid::Principal,
+    created_at: u64,
+    voting_period: Option<VotingPeriod>,
+    voting_ends_at: Option<u64>,
+    rules: DecisionRules,
+    electorate: Option<Electorate>,
+    ledger: Option<candid::Principal>,
+    wait_for_quiet: Option<WaitForQuiet>,
+    deadline_extended_by: u64,
+    secret_ballot: Option<SecretBallot>,
+    reveal_ends_at: Option<u64>,
+    outcome: Option<Outcome>,
+    history: Vec<HistoryEntry>,
+    conviction: Option<Conviction>,
+    jury: Option<Jury>,
+}
+
+/// Lifecycle of a proposal. Allowed transitions are Draft -> Open ->
+/// Closed -> Executed, Open -> Revealing -> Closed for secret ballots,
+/// Open or Revealing -> Tallying -> Closed for proposals with delegated
+/// ballots to cast or counted in rounds, and Draft, Open or Revealing ->
+/// Cancelled.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+enum ProposalStatus {
+    Draft,
+    Open,
+    /// Voting on a secret-ballot proposal ended and committed ballots are
+    /// being revealed.
+    Revealing,
+    /// Voting ended and delegated ballots are being cast, and ballots
+    /// counted in rounds, in batches. The outcome is decided when they are
+    /// done.
+    Tallying,
+    Closed,
+    Executed,
+    Cancelled,
+}
+
+#[derive(CandidType, Deserialize)]
+enum ProposalEvent {
+    StatusChanged(ProposalStatus),
+    DeadlineExtended { from: u64, to: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+struct HistoryEntry {
+    at: u64,
+    event: ProposalEvent,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+enum Outcome {
+    Passed,
+    Rejected,
+    QuorumNotReached,
+    /// The option of a multi-option proposal that was selected.
+    Winner(u32),
+}
+
+/// How the outcome of a proposal is decided when it closes.
+#[derive(CandidType, Deserialize, Clone)]
+struct DecisionRules {
+    /// Minimum voting weight cast for the result to count.
+    quorum: u64,
+    threshold: Threshold,
+    /// Whether `Choice::Pass` votes count toward the quorum.
+    pass_counts_toward_quorum: bool,
+}
+
+impl Default for DecisionRules {
+    fn default() -> Self {
+        DecisionRules {
+            quorum: 0,
+            threshold: Threshold::SimpleMajority,
+            pass_counts_toward_quorum: true,
+        }
+    }
+}
+
+/// Share of approving weight among approve and reject votes needed to pass.
+/// `Pass` votes never count toward the threshold.
+#[derive(CandidType, Deserialize, Clone, Copy)]
+enum Threshold {
+    /// More approvals than rejections.
+    SimpleMajority,
+    /// At least `numerator / denominator` of the votes are approvals, e.g. 2/3.
+    Supermajority { numerator: u32, denominator: u32 },
+}
+
+/// Extends the deadline when a late ballot flips the provisional outcome.
+/// All values are in nanoseconds.
+#[derive(CandidType, Deserialize, Clone)]
+struct WaitForQuiet {
+    /// How close to the deadline a ballot has to be cast to extend it.
+    window: u64,
+    /// How much the deadline is extended by on each flip.
+    extension: u64,
+    /// Upper bound on the sum of all extensions.
+    max_extension: u64,
+}
+
+/// Voters commit to a hidden choice while the proposal is open and reveal it
+/// during a window of `reveal_period` nanoseconds after voting ends.
+#[derive(CandidType, Deserialize, Clone)]
+struct SecretBallot {
+    reveal_period: u64,
+}
+
+/// Jury of a proposal, with the randomness it was drawn from so the draw can
+/// be repeated by anyone.
+#[derive(CandidType, Deserialize, Clone)]
+struct Jury {
+    /// The bytes returned by `raw_rand`.
+    seed: Vec<u8>,
+    jurors: Vec<candid::Principal>,
+}
+
+/// Progress of a proposal in `Tallying`.
+#[derive(CandidType, Deserialize)]
+struct PendingTally {
+    stage: TallyStage,
+    /// Last member of the electorate or voter handled in the current stage
+    /// or round, `None` at its start.
+    cursor: Option<candid::Principal>,
+}
+
+/// Progress of the electorate snapshot of a proposal in `Draft`.
+#[derive(CandidType, Deserialize)]
+struct PendingSnapshot {
+    /// Last member handled.
+    cursor: candid::Principal,
+    /// Whether to open the proposal once the snapshot is complete.
+    open: bool,
+}
+
+#[derive(CandidType, Deserialize)]
+enum TallyStage {
+    /// Casting delegated ballots for the electorate.
+    Delegations,
+    /// Counting the ballots of a proposal counted in rounds. The last round
+    /// is the one being counted, and `cast` is the weight on ballots other
+    /// than `Pass`.
+    Counting { rounds: Vec<TallyRound>, cast: u64 },
+}
+
+/// Hash committed to by a voter on a secret-ballot proposal.
+#[derive(CandidType, Deserialize)]
+struct Commitment {
+    hash: Vec<u8>,
+    committed_at: u64,
+}
+
+#[derive(CandidType)]
+struct CommitmentEntry {
+    voter: candid::Principal,
+    commitment: Commitment,
+    /// Whether the commitment was revealed and its ballot counted.
+    revealed: bool,
+}
+
+/// When voting on a proposal ends, in nanoseconds: either an absolute time
+/// since the epoch or a duration counted from the moment it is opened.
+#[derive(CandidType, Deserialize, Clone)]
+enum VotingPeriod {
+    EndsAt(u64),
+    Duration(u64),
+}
+
+/// ICRC-1 account.
+#[derive(CandidType)]
+struct Account {
+    owner: candid::Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+/// Arguments of `icrc2_transfer_from`.
+#[derive(CandidType)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: candid::Nat,
+    fee: Option<candid::Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+/// Arguments of `icrc1_transfer`.
+#[derive(CandidType)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: candid::Nat,
+    fee: Option<candid::Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+/// Errors of `icrc1_transfer` and `icrc2_transfer_from`.
+#[derive(CandidType, Deserialize, Debug)]
+enum LedgerError {
+    BadFee { expected_fee: candid::Nat },
+    BadBurn { min_burn_amount: candid::Nat },
+    InsufficientFunds { balance: candid::Nat },
+    InsufficientAllowance { allowance: candid::Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: candid::Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: candid::Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy)]
+struct Electorate {
+    size: u64,
+    total_weight: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum ProposalKind {
+    /// Approve, Reject or Pass.
+    YesNo,
+    /// One of 2 to 16 labelled options, or Pass.
+    MultiOption(Vec<String>),
+    /// A ranking of 2 to 16 labelled options, decided by instant runoff.
+    RankedChoice(Vec<String>),
+    /// Any subset of 2 to 16 labelled options; the most approved one wins.
+    Approval(Vec<String>),
+    /// Scores of 0 to 5 for each of 2 to 16 labelled options, followed by
+    /// an automatic runoff between the two highest scoring ones.
+    Star(Vec<String>),
+    /// Votes spread over 2 to 16 labelled options out of a budget of
+    /// `credits` per voter, where n votes on an option cost n² credits.
+    Quadratic { options: Vec<String>, credits: u64 },
+    /// A funding request passing once the conviction of the support staked
+    /// on it reaches a threshold scaled by the requested amount.
+    Conviction(ConvictionParams),
+    /// A routine proposal passing at its deadline unless the weight of the
+    /// `Reject` ballots, counted as vetoes, reaches `veto_threshold`.
+    Optimistic { veto_threshold: u64 },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct ConvictionParams {
+    /// Amount of treasury funds the proposal asks for.
+    requested: u64,
+    /// Time in nanoseconds for the conviction to close half of the distance
+    /// to the support currently staked.
+    half_life: u64,
+    /// Conviction needed per requested unit, in basis points.
+    threshold_bps: u32,
+}
+
+/// Conviction of a conviction-voting proposal as of `updated_at`.
+#[derive(CandidType, Deserialize, Clone, Copy)]
+struct Conviction {
+    value: f64,
+    updated_at: u64,
+}
+
+#[derive(CandidType)]
+struct ConvictionStatus {
+    /// Conviction accumulated up to now.
+    conviction: f64,
+    /// Support currently staked.
+    staked: u64,
+    threshold: f64,
+    /// When the proposal passes if the support stays as it is.
+    passes_at: Option<u64>,
+}
+
+/// One counting round of a proposal decided over several rounds.
+#[derive(CandidType, Deserialize, Clone)]
+struct TallyRound {
+    /// Weight counted for each option, in option order.
+    counts: Vec<u64>,
+    /// Weight of ballots with no option left in the race.
+    exhausted: u64,
+    /// Options removed at the end of this round.
+    eliminated: Vec<u32>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TallyRounds {
+    rounds: Vec<TallyRound>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Default)]
+struct OptionTally {
+    headcount: u64,
+    weighted: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Default)]
+struct Tally {
+    approve: u64,
+    reject: u64,
+    pass: u64,
+}
+
+impl Tally {
+    /// Options are tallied per option, so they have no counter here.
+    fn counter(&mut self, choice: &Choice) -> Option<&mut u64> {
+        match choice {
+            Choice::Approve | Choice::Stake(_) => Some(&mut self.approve),
+            Choice::Reject => Some(&mut self.reject),
+            Choice::Pass => Some(&mut self.pass),
+            Choice::Option(_)
+            | Choice::Ranking(_)
+            | Choice::Approvals(_)
+            | Choice::Scores(_)
+            | Choice::Votes(_) => None,
+        }
+    }
+
+    fn add(&mut self, choice: &Choice, amount: u64) {
+        if let Some(counter) = self.counter(choice) {
+            *counter = counter.saturating_add(amount);
+        }
+    }
+
+    fn remove(&mut self, choice: &Choice, amount: u64) {
+        if let Some(counter) = self.counter(choice) {
+            *counter = counter.saturating_sub(amount);
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.approve.saturating_add(self.reject).saturating_add(self.pass)
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct Ballot {
+    choice: Choice,
+    /// Voting weight the ballot was counted with.
+    weight: u64,
+    cast_at: u64,
+    reason: Option<String>,
+    /// For ballots cast by delegation, the voter whose choice they follow.
+    via: Option<candid::Principal>,
+}
+
+/// A ballot that was changed or retracted while the proposal was open.
+#[derive(CandidType, Deserialize)]
+struct PastBallot {
+    ballot: Ballot,
+    /// When the ballot was replaced or retracted.
+    superseded_at: u64,
+    retracted: bool,
+}
+
+/// Earlier ballots of a voter on a proposal, oldest first.
+#[derive(CandidType, Deserialize, Default)]
+struct BallotHistory {
+    ballots: Vec<PastBallot>,
+}
+
+#[derive(CandidType)]
+struct BallotEntry {
+    voter: candid::Principal,
+    ballot: Ballot,
+}
+
+/// Principal wrapper so principals can be used inside stable map keys.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct StablePrincipal(candid::Principal);
+
+impl Default for StablePrincipal {
+    fn default() -> Self {
+        StablePrincipal(candid::Principal::anonymous())
+    }
+}
+
+impl Storable for StablePrincipal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StablePrincipal(candid::Principal::from_slice(bytes.as_ref()))
+    }
+}
+
+impl BoundedStorable for StablePrincipal {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize)]
+struct Member {
+    joined_at: u64,
+    /// Voting weight of the member's ballots.
+    weight: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum Role {
+    /// Manages roles, members and settings. Implies every other role.
+    Admin,
+    /// May end or cancel any proposal.
+    Moderator,
+    /// May create proposals when creation is gated.
+    Proposer,
+    /// May vote when voting is gated. Granted with membership.
+    Voter,
+    /// May read the member and role registries.
+    Observer,
+}
+
+impl Role {
+    const ALL: [Role; 5] = [Role::Admin, Role::Moderator, Role::Proposer, Role::Voter, Role::Observer];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Subject area of a proposal. Members may delegate their vote per topic.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Default)]
+enum Topic {
+    #[default]
+    General,
+    Governance,
+    Finance,
+    Engineering,
+    Membership,
+}
+
+impl Topic {
+    const ALL: [Topic; 5] = [
+        Topic::General,
+        Topic::Governance,
+        Topic::Finance,
+        Topic::Engineering,
+        Topic::Membership,
+    ];
+
+    fn id(self) -> u8 {
+        self as u8
+    }
+}
+
+#[derive(CandidType)]
+struct RoleEntry {
+    principal: candid::Principal,
+    roles: Vec<Role>,
+}
+
+#[derive(CandidType)]
+struct MemberEntry {
+    principal: candid::Principal,
+    member: Member,
+}
+
+#[derive(CandidType)]
+struct Delegation {
+    delegator: candid::Principal,
+    delegate: candid::Principal,
+}
+
+/// Canister-wide settings, kept in stable memory.
+#[derive(CandidType, Deserialize, Clone)]
+struct Config {
+    /// Only members holding the Proposer role may create proposals.
+    members_only_create: bool,
+    /// Only members holding the Voter role may vote.
+    members_only_vote: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            members_only_create: true,
+            members_only_vote: true,
+        }
+    }
+}
+
 #[derive(CandidType, Deserialize)]
 struct CreateProposal {
     description: String,
-    is_active: bool,
+    open: bool,
+    voting_period: Option<VotingPeriod>,
+    rules: Option<DecisionRules>,
+    /// Defaults to `YesNo`.
+    kind: Option<ProposalKind>,
+    /// Defaults to `General`.
+    topic: Option<Topic>,
+    /// Defaults to `true`.
+    allow_vote_changes: Option<bool>,
+    /// Weigh votes by the tokens each voter escrows on the proposal from this
+    /// ICRC-2 ledger instead of by member weights. Escrowed tokens stay with
+    /// the proposal until voting ends, so they cannot vote twice.
+    ledger: Option<candid::Principal>,
+    /// Requires a voting period.
+    wait_for_quiet: Option<WaitForQuiet>,
+    /// Requires a voting period, and excludes wait-for-quiet.
+    secret_ballot: Option<SecretBallot>,
+    /// Withholds the tallies until the proposal closes. Defaults to `false`.
+    blind_results: Option<bool>,
+    /// Restricts voting to this many members drawn at random from the
+    /// registry. Fixed at creation.
+    jury_size: Option<u32>,
 }
 
 impl Storable for Proposal {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -57,98 +711,3210 @@ impl BoundedStorable for Proposal {
     const IS_FIXED_SIZE: bool = false;
 }
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+impl Storable for RawProposal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
 
-    static PROPOSAL_MAP: RefCell<StableBTreeMap<u64, Proposal, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))))
-    );
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RawProposal(bytes.into_owned())
+    }
 }
 
-#[query]
-fn get_proposal(key: u64) -> Option<Proposal> {
-    PROPOSAL_MAP.with(|p| p.borrow().get(&key))
+impl BoundedStorable for RawProposal {
+    const MAX_SIZE: u32 = MAX_VALUE_SIZE;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-#[query]
-fn get_proposal_count() -> u64 {
-    PROPOSAL_MAP.with(|p| p.borrow().len())
+impl Storable for Member {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-#[update]
-fn create_proposal(key: u64, proposal: CreateProposal) -> Option<Proposal> {
-    let value: Proposal = Proposal {
-        description: proposal.description,
-        approve: 0u32,
-        reject: 0u32,
-        pass: 0u32,
-        is_active: proposal.is_active,
-        voted: vec![],
-        owner: caller(),
-    };
+impl BoundedStorable for Member {
+    const MAX_SIZE: u32 = MAX_MEMBER_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    PROPOSAL_MAP.with(|p| p.borrow_mut().insert(key, value))
+impl Storable for Config {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-#[update]
-fn edit_proposal(key: u64, proposal: CreateProposal) -> Result<(), VoteError> {
-    PROPOSAL_MAP.with(|p| {
-        let old_proposal_opt: Option<Proposal> = p.borrow().get(&key);
-        let old_proposal = old_proposal_opt.ok_or(VoteError::NoSuchProposal)?;
+impl Storable for Ballot {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-        if caller() != old_proposal.owner {
-            return Err(VoteError::AccessRejected);
-        };
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
 
-        let value: Proposal = Proposal {
-            description: proposal.description,
-            is_active: proposal.is_active,
-            ..old_proposal
-        };
+impl BoundedStorable for Ballot {
+    const MAX_SIZE: u32 = MAX_BALLOT_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-        p.borrow_mut().insert(key, value).ok_or(VoteError::UpdateError("Insert failed".to_string()))
-    })
+impl Storable for Commitment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
 }
 
-#[update]
-fn end_proposal(key: u64) -> Result<(), VoteError> {
-    PROPOSAL_MAP.with(|p| {
-        let proposal_opt: Option<Proposal> = p.borrow().get(&key);
-        let mut proposal = proposal_opt.ok_or(VoteError::NoSuchProposal)?;
+impl BoundedStorable for Commitment {
+    const MAX_SIZE: u32 = MAX_COMMITMENT_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-        if caller() != proposal.owner {
-            return Err(VoteError::AccessRejected);
-        };
+impl Storable for BallotHistory {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-        proposal.is_active = false;
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
 
-        p.borrow_mut().insert(key, proposal).ok_or(VoteError::UpdateError("Insert failed".to_string()))
-    })
+impl BoundedStorable for BallotHistory {
+    const MAX_SIZE: u32 = MAX_BALLOT_HISTORY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-#[update]
-fn vote(key: u64, choice: Choice) -> Result<(), VoteError> {
-    PROPOSAL_MAP.with(|p| {
-        let proposal_opt: Option<Proposal> = p.borrow().get(&key);
-        let mut proposal = proposal_opt.ok_or(VoteError::NoSuchProposal)?;
+impl Storable for TallyRounds {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
 
-        let caller = caller();
+impl BoundedStorable for TallyRounds {
+    const MAX_SIZE: u32 = MAX_ROUNDS_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-        if proposal.voted.contains(&caller) {
-            return Err(VoteError::AlreadyVoted);
-        } else if !proposal.is_active {
-            return Err(VoteError::ProposalIsNotActive);
-        };
+impl Storable for PendingTally {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
 
-        match choice {
-            Choice::Approve => proposal.approve += 1,
-            Choice::Reject => proposal.reject += 1,
-            Choice::Pass => proposal.pass += 1,
-        };
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
 
-        proposal.voted.push(caller);
+impl BoundedStorable for PendingTally {
+    const MAX_SIZE: u32 = MAX_PENDING_TALLY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
 
-        p.borrow_mut().insert(key, proposal).ok_or(VoteError::UpdateError("Insert failed".to_string()))
-    })
+impl Storable for PendingSnapshot {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PendingSnapshot {
+    const MAX_SIZE: u32 = MAX_PENDING_SNAPSHOT_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static PROPOSAL_MAP: RefCell<StableBTreeMap<u64, Proposal, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))))
+    );
+
+    // Next proposal ID to hand out. On first use it starts right after the
+    // highest key already in PROPOSAL_MAP, so records created with caller
+    // supplied keys are never reused.
+    static NEXT_PROPOSAL_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+            PROPOSAL_MAP.with(|p| p.borrow().last_key_value().map_or(0, |(key, _)| key + 1)),
+        )
+        .expect("Cannot create the proposal ID counter")
+    );
+
+    // One ballot per (proposal ID, voter), kept out of the proposal record so
+    // the number of voters is not bounded by MAX_VALUE_SIZE.
+    static BALLOTS: RefCell<StableBTreeMap<(u64, StablePrincipal), Ballot, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))))
+    );
+
+    static MEMBERS: RefCell<StableBTreeMap<StablePrincipal, Member, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))))
+    );
+
+    static CONFIG: RefCell<StableCell<Config, Memory>> = RefCell::new(
+        StableCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))), Config::default())
+            .expect("Cannot create the config cell")
+    );
+
+    // Roles of each principal as a bit set of `Role::bit`.
+    static ROLES: RefCell<StableBTreeMap<StablePrincipal, u8, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))))
+    );
+
+    // Weight of each eligible voter per proposal: frozen at creation for
+    // member proposals, or the tokens each voter escrowed and has not
+    // withdrawn yet for token proposals.
+    static ELECTORATE: RefCell<StableBTreeMap<(u64, StablePrincipal), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))))
+    );
+
+    // Counting rounds of closed proposals decided over several rounds, kept
+    // so their result can be audited.
+    static TALLY_ROUNDS: RefCell<StableBTreeMap<u64, TallyRounds, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))))
+    );
+
+    // Credits spent by each voter on a quadratic proposal.
+    static CREDITS: RefCell<StableBTreeMap<(u64, StablePrincipal), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))))
+    );
+
+    // Delegate of each member who hands their vote to someone else.
+    static DELEGATIONS: RefCell<StableBTreeMap<StablePrincipal, StablePrincipal, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))))
+    );
+
+    // Delegate of each member per topic, keyed by (`Topic::id`, delegator).
+    // Topics without an entry fall back to DELEGATIONS.
+    static TOPIC_DELEGATIONS: RefCell<StableBTreeMap<(u8, StablePrincipal), StablePrincipal, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))))
+    );
+
+    // Changed and retracted ballots per (proposal ID, voter).
+    static BALLOT_HISTORY: RefCell<StableBTreeMap<(u64, StablePrincipal), BallotHistory, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))))
+    );
+
+    // Commitments of voters on secret-ballot proposals, kept after the
+    // reveal so unrevealed ones can be audited through `list_commitments`.
+    static COMMITMENTS: RefCell<StableBTreeMap<(u64, StablePrincipal), Commitment, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))))
+    );
+
+    // Progress of proposals in `Tallying`, so their batches resume after an
+    // upgrade.
+    static PENDING_TALLIES: RefCell<StableBTreeMap<u64, PendingTally, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))))
+    );
+
+    // Progress of electorate snapshots taken over several messages, so their
+    // batches resume after an upgrade.
+    static PENDING_SNAPSHOTS: RefCell<StableBTreeMap<u64, PendingSnapshot, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))))
+    );
+
+    // Timers ending the voting period or reveal window of proposals. Timers do not survive
+    // upgrades, so they are re-armed from PROPOSAL_MAP in post_upgrade.
+    // Support each member stakes per conviction proposal, keyed by (member,
+    // proposal ID), so the stakes across open proposals stay within their
+    // weight.
+    static STAKES: RefCell<StableBTreeMap<(StablePrincipal, u64), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))))
+    );
+
+    static DEADLINE_TIMERS: RefCell<HashMap<u64, TimerId>> = RefCell::new(HashMap::new());
+}
+
+fn allocate_proposal_id() -> Result<u64, VoteError> {
+    NEXT_PROPOSAL_ID.with(|c| {
+        let mut cell = c.borrow_mut();
+        let id: u64 = *cell.get();
+        cell.set(id + 1)
+            .map_err(|e| VoteError::UpdateError(format!("Cannot advance proposal ID: {:?}", e)))?;
+        Ok(id)
+    })
+}
+
+fn voting_deadline(period: &Option<VotingPeriod>) -> Result<Option<u64>, VoteError> {
+    let now: u64 = time();
+    match period {
+        None => Ok(None),
+        Some(VotingPeriod::EndsAt(ends_at)) if *ends_at > now => Ok(Some(*ends_at)),
+        Some(VotingPeriod::Duration(duration)) if *duration > 0 => now
+            .checked_add(*duration)
+            .map(Some)
+            .ok_or(VoteError::InvalidVotingPeriod),
+        Some(_) => Err(VoteError::InvalidVotingPeriod),
+    }
+}
+
+fn validate_rules(rules: &DecisionRules) -> Result<(), VoteError> {
+    match rules.threshold {
+        Threshold::SimpleMajority => Ok(()),
+        Threshold::Supermajority { numerator, denominator } if 0 < numerator && numerator <= denominator => Ok(()),
+        Threshold::Supermajority { .. } => Err(VoteError::InvalidRules),
+    }
+}
+
+fn validate_wait_for_quiet(proposal: &CreateProposal) -> Result<(), VoteError> {
+    match &proposal.wait_for_quiet {
+        Some(_) if proposal.voting_period.is_none() => Err(VoteError::InvalidVotingPeriod),
+        Some(wfq) if wfq.window == 0 || wfq.extension == 0 => Err(VoteError::InvalidVotingPeriod),
+        Some(wfq) if wfq.max_extension.div_ceil(wfq.extension) > MAX_DEADLINE_EXTENSIONS => {
+            Err(VoteError::InvalidVotingPeriod)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_secret_ballot(proposal: &CreateProposal) -> Result<(), VoteError> {
+    match &proposal.secret_ballot {
+        Some(_) if proposal.voting_period.is_none() || proposal.wait_for_quiet.is_some() => {
+            Err(VoteError::InvalidVotingPeriod)
+        }
+        Some(secret) if secret.reveal_period == 0 => Err(VoteError::InvalidVotingPeriod),
+        _ => Ok(()),
+    }
+}
+
+/// Wait-for-quiet extends the deadline whenever the provisional outcome flips,
+/// which would give away the withheld tallies of a blind proposal.
+fn validate_blind_results(proposal: &CreateProposal) -> Result<(), VoteError> {
+    if proposal.blind_results == Some(true) && proposal.wait_for_quiet.is_some() {
+        return Err(VoteError::InvalidVotingPeriod);
+    }
+    Ok(())
+}
+
+/// Hash a voter commits to: SHA-256 of `choice_bytes(choice)` followed by the
+/// salt.
+fn commitment_hash(choice: &Choice, salt: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(choice_bytes(choice));
+    hasher.update(salt);
+    hasher.finalize().to_vec()
+}
+
+/// Canonical encoding of a choice for commitments, independent of Candid so
+/// commitments stay revealable when `Choice` gains variants. It is one tag
+/// byte (Approve 0, Reject 1, Pass 2, Option 3, Ranking 4, Approvals 5,
+/// Scores 6, Votes 7, Stake 8), then for `Option` the index as a
+/// little-endian u32, for `Stake` the amount as a little-endian u64, and for
+/// the list variants their length as a little-endian u32 followed by
+/// every element as a little-endian u32, or as a single byte for `Scores`.
+fn choice_bytes(choice: &Choice) -> Vec<u8> {
+    let list = |tag: u8, items: &[u32]| -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![tag];
+        bytes.extend_from_slice(&(items.len() as u32).to_le_bytes());
+        items.iter().for_each(|item| bytes.extend_from_slice(&item.to_le_bytes()));
+        bytes
+    };
+
+    match choice {
+        Choice::Approve => vec![0],
+        Choice::Reject => vec![1],
+        Choice::Pass => vec![2],
+        Choice::Option(index) => [&[3][..], &index.to_le_bytes()].concat(),
+        Choice::Ranking(ranking) => list(4, ranking),
+        Choice::Approvals(approved) => list(5, approved),
+        Choice::Scores(scores) => [&[6][..], &(scores.len() as u32).to_le_bytes(), scores].concat(),
+        Choice::Votes(votes) => list(7, votes),
+        Choice::Stake(amount) => [&[8][..], &amount.to_le_bytes()].concat(),
+    }
+}
+
+/// Whether the tallies and ballots of a proposal are withheld. Blind and
+/// secret-ballot proposals only show them once they are closed.
+fn tallies_hidden(proposal: &Proposal) -> bool {
+    (proposal.blind_results || proposal.secret_ballot.is_some())
+        && !matches!(proposal.status, ProposalStatus::Closed | ProposalStatus::Executed)
+}
+
+fn proposal_view(proposal: Proposal) -> ProposalView {
+    let hidden: bool = tallies_hidden(&proposal);
+
+    ProposalView {
+        description: proposal.description,
+        kind: proposal.kind,
+        topic: proposal.topic,
+        allow_vote_changes: proposal.allow_vote_changes,
+        blind_results: proposal.blind_results,
+        headcount: (!hidden).then_some(proposal.headcount),
+        weighted: (!hidden).then_some(proposal.weighted),
+        option_tallies: (!hidden).then_some(proposal.option_tallies),
+        status: proposal.status,
+        owner: proposal.owner,
+        created_at: proposal.created_at,
+        voting_period: proposal.voting_period,
+        voting_ends_at: proposal.voting_ends_at,
+        rules: proposal.rules,
+        electorate: proposal.electorate,
+        ledger: proposal.ledger,
+        wait_for_quiet: proposal.wait_for_quiet,
+        deadline_extended_by: proposal.deadline_extended_by,
+        secret_ballot: proposal.secret_ballot,
+        reveal_ends_at: proposal.reveal_ends_at,
+        outcome: proposal.outcome,
+        history: proposal.history,
+        conviction: proposal.conviction.filter(|_| !hidden),
+        jury: proposal.jury,
+    }
+}
+
+fn decide(rules: &DecisionRules, tally: &Tally) -> Outcome {
+    let (approve, reject) = (tally.approve as u128, tally.reject as u128);
+    let turnout: u128 = approve + reject + if rules.pass_counts_toward_quorum { tally.pass as u128 } else { 0 };
+    if turnout < rules.quorum as u128 {
+        return Outcome::QuorumNotReached;
+    }
+
+    let passed: bool = match rules.threshold {
+        Threshold::SimpleMajority => approve > reject,
+        Threshold::Supermajority { numerator, denominator } => {
+            approve > 0 && approve * denominator as u128 >= (approve + reject) * numerator as u128
+        }
+    };
+
+    if passed {
+        Outcome::Passed
+    } else {
+        Outcome::Rejected
+    }
+}
+
+fn options(kind: &ProposalKind) -> &[String] {
+    match kind {
+        ProposalKind::YesNo => &[],
+        ProposalKind::MultiOption(options)
+        | ProposalKind::RankedChoice(options)
+        | ProposalKind::Approval(options)
+        | ProposalKind::Star(options)
+        | ProposalKind::Quadratic { options, .. } => options,
+        ProposalKind::Conviction(_) | ProposalKind::Optimistic { .. } => &[],
+    }
+}
+
+/// Checks the option labels of `kind`. Labels are stored in the proposal, so
+/// together they also count toward `check_proposal_size`.
+fn validate_kind(kind: &ProposalKind) -> Result<(), VoteError> {
+    let options: &[String] = options(kind);
+    let valid: bool = match kind {
+        ProposalKind::YesNo | ProposalKind::Conviction(_) | ProposalKind::Optimistic { .. } => true,
+        ProposalKind::Quadratic { credits: 0, .. } => false,
+        _ => {
+            (2..=MAX_OPTIONS).contains(&options.len())
+                && options.iter().all(|label| !label.is_empty() && label.len() <= MAX_OPTION_LABEL_LEN)
+        }
+    };
+
+    if !valid {
+        return Err(VoteError::InvalidOptions);
+    }
+    Ok(())
+}
+
+/// Checks the settings of conviction and optimistic proposals. Conviction
+/// proposals stay open until they pass or are ended, so they take no voting
+/// period; optimistic ones pass at their deadline, so they need one.
+fn validate_kind_rules(kind: &ProposalKind, voting_period: &Option<VotingPeriod>) -> Result<(), VoteError> {
+    match kind {
+        ProposalKind::Conviction(_) if voting_period.is_some() => Err(VoteError::InvalidVotingPeriod),
+        ProposalKind::Conviction(params)
+            if params.requested == 0 || params.half_life == 0 || params.threshold_bps == 0 =>
+        {
+            Err(VoteError::InvalidRules)
+        }
+        ProposalKind::Optimistic { .. } if voting_period.is_none() => Err(VoteError::InvalidVotingPeriod),
+        ProposalKind::Optimistic { veto_threshold: 0 } => Err(VoteError::InvalidRules),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that a new or edited proposal stays under MAX_VALUE_SIZE once it
+/// has grown by PROPOSAL_GROWTH, so storing it never fails later on.
+fn check_proposal_size(proposal: &Proposal) -> Result<(), VoteError> {
+    let size: usize = Encode!(proposal)
+        .map_err(|e| VoteError::UpdateError(format!("Cannot encode proposal: {}", e)))?
+        .len();
+    if size + PROPOSAL_GROWTH > MAX_VALUE_SIZE as usize {
+        return Err(VoteError::ProposalTooLarge);
+    }
+    Ok(())
+}
+
+fn validate_jury_size(size: u32) -> Result<(), VoteError> {
+    let members: u64 = MEMBERS.with(|m| m.borrow().len());
+    if size == 0 || size > MAX_JURY_SIZE || size as u64 > members {
+        return Err(VoteError::InvalidJurySize);
+    }
+    Ok(())
+}
+
+/// Jury of `size` members with the longest principals and a `raw_rand`-sized
+/// seed. It stands in for the jury in `check_proposal_size` until the real
+/// one is drawn, which can only be smaller.
+fn largest_jury(size: u32) -> Jury {
+    Jury {
+        seed: vec![0; 32],
+        jurors: vec![candid::Principal::from_slice(&[0; 29]); size as usize],
+    }
+}
+
+/// Draws `size` distinct members from the registry in principal order with a
+/// partial Fisher-Yates shuffle. Swap `i` picks among the members left using
+/// the first 8 bytes of SHA-256(seed || i as little-endian u64), read as a
+/// little-endian integer, modulo their number.
+fn draw_jury(seed: &[u8], size: usize) -> Vec<candid::Principal> {
+    let mut members: Vec<candid::Principal> =
+        MEMBERS.with(|m| m.borrow().iter().map(|(principal, _)| principal.0).collect());
+
+    for i in 0..size {
+        let mut hasher = Sha256::new();
+        hasher.update(seed);
+        hasher.update((i as u64).to_le_bytes());
+        let digest = hasher.finalize();
+
+        let draw: u64 = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        let j: usize = i + (draw % (members.len() - i) as u64) as usize;
+        members.swap(i, j);
+    }
+
+    members.truncate(size);
+    members
+}
+
+/// Checks that `indices` are distinct options of a proposal with
+/// `option_count` options.
+fn valid_option_set(indices: &[u32], option_count: usize) -> bool {
+    let mut seen: Vec<bool> = vec![false; option_count];
+    indices.iter().all(|index| {
+        let index = *index as usize;
+        index < option_count && !std::mem::replace(&mut seen[index], true)
+    })
+}
+
+fn validate_choice(kind: &ProposalKind, choice: &Choice) -> Result<(), VoteError> {
+    let valid: bool = match (kind, choice) {
+        (_, Choice::Pass) => true,
+        (ProposalKind::YesNo, Choice::Approve | Choice::Reject) => true,
+        (ProposalKind::MultiOption(options), Choice::Option(index)) => (*index as usize) < options.len(),
+        (ProposalKind::RankedChoice(options), Choice::Ranking(ranking)) => {
+            !ranking.is_empty() && valid_option_set(ranking, options.len())
+        }
+        (ProposalKind::Approval(options), Choice::Approvals(approved)) => {
+            !approved.is_empty() && valid_option_set(approved, options.len())
+        }
+        (ProposalKind::Star(options), Choice::Scores(scores)) => {
+            scores.len() == options.len() && scores.iter().all(|score| *score <= MAX_STAR_SCORE)
+        }
+        (ProposalKind::Conviction(_), Choice::Stake(amount)) => *amount > 0,
+        (ProposalKind::Optimistic { .. }, Choice::Reject) => true,
+        (ProposalKind::Quadratic { options, .. }, Choice::Votes(votes)) => {
+            votes.len() == options.len() && votes.iter().any(|n| *n > 0)
+        }
+        _ => false,
+    };
+
+    if !valid {
+        return Err(VoteError::InvalidChoice);
+    }
+    Ok(())
+}
+
+/// Credits spent by a quadratic ballot: the sum of the squared votes.
+fn quadratic_cost(votes: &[u32]) -> u64 {
+    votes
+        .iter()
+        .fold(0u64, |cost, n| cost.saturating_add((*n as u64).saturating_mul(*n as u64)))
+}
+
+/// Credits a ballot costs on a quadratic proposal. Ballots on other kinds of
+/// proposals cost nothing.
+fn ballot_cost(proposal: &Proposal, choice: &Choice) -> u64 {
+    match (&proposal.kind, choice) {
+        (ProposalKind::Quadratic { .. }, Choice::Votes(votes)) => quadratic_cost(votes),
+        _ => 0,
+    }
+}
+
+/// Charges a ballot to the voter's budget on a quadratic proposal, after
+/// refunding the `refund` credits of the ballot it replaces. Other kinds of
+/// proposals have no budget.
+fn spend_credits(
+    key: u64,
+    proposal: &Proposal,
+    voter: candid::Principal,
+    choice: &Choice,
+    refund: u64,
+) -> Result<(), VoteError> {
+    let ProposalKind::Quadratic { credits, .. } = &proposal.kind else {
+        return Ok(());
+    };
+
+    let cost: u64 = ballot_cost(proposal, choice);
+    let spent: u64 = CREDITS
+        .with(|c| c.borrow().get(&(key, StablePrincipal(voter))))
+        .unwrap_or(0)
+        .saturating_sub(refund);
+    let remaining: u64 = credits.saturating_sub(spent);
+    if cost > remaining {
+        return Err(VoteError::InsufficientCredits { cost, remaining });
+    }
+
+    CREDITS.with(|c| c.borrow_mut().insert((key, StablePrincipal(voter)), spent + cost));
+    Ok(())
+}
+
+/// Support `voter` has staked on open conviction proposals other than `key`.
+/// Stakes left on proposals that are no longer open are dropped.
+fn staked_elsewhere(voter: candid::Principal, key: u64) -> u64 {
+    let range = (
+        Bound::Included((StablePrincipal(voter), 0)),
+        Bound::Included((StablePrincipal(voter), u64::MAX)),
+    );
+    let stakes: Vec<(u64, u64)> = STAKES.with(|s| s.borrow().range(range).map(|((_, id), amount)| (id, amount)).collect());
+
+    let mut staked: u64 = 0;
+    for (id, amount) in stakes {
+        if id == key {
+            continue;
+        }
+        match PROPOSAL_MAP.with(|p| p.borrow().get(&id)).is_some_and(|proposal| proposal.status == ProposalStatus::Open) {
+            true => staked = staked.saturating_add(amount),
+            false => {
+                STAKES.with(|s| s.borrow_mut().remove(&(StablePrincipal(voter), id)));
+            }
+        }
+    }
+    staked
+}
+
+/// Records the support a conviction ballot stakes, which must fit in `weight`
+/// less what the voter has staked on other open conviction proposals, and
+/// returns it as the weight the ballot counts with. Other ballots on a
+/// conviction proposal release the voter's stake; ballots on other kinds of
+/// proposals count with `weight`.
+fn stake_support(
+    key: u64,
+    proposal: &Proposal,
+    voter: candid::Principal,
+    choice: &Choice,
+    weight: u64,
+) -> Result<u64, VoteError> {
+    let ProposalKind::Conviction(_) = proposal.kind else {
+        return Ok(weight);
+    };
+    let Choice::Stake(requested) = *choice else {
+        STAKES.with(|s| s.borrow_mut().remove(&(StablePrincipal(voter), key)));
+        return Ok(weight);
+    };
+
+    let available: u64 = weight.saturating_sub(staked_elsewhere(voter, key));
+    if requested > available {
+        return Err(VoteError::InsufficientStake { requested, available });
+    }
+
+    STAKES.with(|s| s.borrow_mut().insert((StablePrincipal(voter), key), requested));
+    Ok(requested)
+}
+
+/// (option, weight multiplier) pairs a choice adds to the option tallies.
+fn counted_options(choice: &Choice) -> Vec<(u32, u64)> {
+    match choice {
+        Choice::Option(index) => vec![(*index, 1)],
+        Choice::Ranking(ranking) => ranking.first().map(|index| (*index, 1)).into_iter().collect(),
+        Choice::Approvals(approved) => approved.iter().map(|index| (*index, 1)).collect(),
+        Choice::Scores(scores) => (0u32..)
+            .zip(scores.iter())
+            .filter(|(_, score)| **score > 0)
+            .map(|(index, score)| (index, *score as u64))
+            .collect(),
+        Choice::Votes(votes) => (0u32..)
+            .zip(votes.iter())
+            .filter(|(_, n)| **n > 0)
+            .map(|(index, n)| (index, *n as u64))
+            .collect(),
+        Choice::Approve | Choice::Reject | Choice::Pass | Choice::Stake(_) => vec![],
+    }
+}
+
+fn conviction_threshold(params: &ConvictionParams) -> f64 {
+    params.requested as f64 * params.threshold_bps as f64 / 10_000.0
+}
+
+/// Conviction reached `elapsed` nanoseconds after it was `value`, with
+/// `staked` support throughout: every `half_life` it closes half of the
+/// distance to the staked support.
+fn conviction_after(value: f64, staked: f64, half_life: u64, elapsed: u64) -> f64 {
+    staked + (value - staked) * 0.5f64.powf(elapsed as f64 / half_life as f64)
+}
+
+/// Brings the conviction of a conviction-voting proposal up to now under the
+/// support staked since its last update. Called before the support changes.
+fn accrue_conviction(proposal: &mut Proposal) {
+    let ProposalKind::Conviction(params) = &proposal.kind else {
+        return;
+    };
+
+    let now: u64 = time();
+    let value: f64 = proposal.conviction.map_or(0.0, |conviction| {
+        let elapsed: u64 = now.saturating_sub(conviction.updated_at);
+        conviction_after(conviction.value, proposal.weighted.approve as f64, params.half_life, elapsed)
+    });
+    proposal.conviction = Some(Conviction { value, updated_at: now });
+}
+
+/// When the conviction of a conviction-voting proposal reaches its threshold
+/// if the staked support stays as it is, or `None` if it never does.
+fn conviction_passes_at(proposal: &Proposal) -> Option<u64> {
+    let ProposalKind::Conviction(params) = &proposal.kind else {
+        return None;
+    };
+    let conviction: Conviction = proposal.conviction?;
+    let threshold: f64 = conviction_threshold(params);
+    let staked: f64 = proposal.weighted.approve as f64;
+
+    if conviction.value >= threshold {
+        return Some(conviction.updated_at);
+    } else if staked <= threshold {
+        return None;
+    }
+
+    let elapsed: f64 = params.half_life as f64 * ((staked - conviction.value) / (staked - threshold)).log2();
+    Some(conviction.updated_at.saturating_add(elapsed.ceil() as u64))
+}
+
+fn count_ballot(proposal: &mut Proposal, choice: &Choice, weight: u64) {
+    accrue_conviction(proposal);
+    proposal.headcount.add(choice, 1);
+    proposal.weighted.add(choice, weight);
+
+    for (index, multiplier) in counted_options(choice) {
+        let option: &mut OptionTally = &mut proposal.option_tallies[index as usize];
+        option.headcount += 1;
+        option.weighted = option.weighted.saturating_add(weight.saturating_mul(multiplier));
+    }
+}
+
+/// Takes a ballot counted by `count_ballot` back out of the tallies.
+fn uncount_ballot(proposal: &mut Proposal, choice: &Choice, weight: u64) {
+    accrue_conviction(proposal);
+    proposal.headcount.remove(choice, 1);
+    proposal.weighted.remove(choice, weight);
+
+    for (index, multiplier) in counted_options(choice) {
+        let option: &mut OptionTally = &mut proposal.option_tallies[index as usize];
+        option.headcount = option.headcount.saturating_sub(1);
+        option.weighted = option.weighted.saturating_sub(weight.saturating_mul(multiplier));
+    }
+}
+
+/// Picks the winning option: under simple majority the option with strictly
+/// more weight than any other, under a supermajority the top option holding
+/// at least that share of all option weight.
+fn decide_options(rules: &DecisionRules, options: &[OptionTally], pass: u64) -> Outcome {
+    let cast: u128 = options.iter().map(|option| option.weighted as u128).sum();
+    let turnout: u128 = cast + if rules.pass_counts_toward_quorum { pass as u128 } else { 0 };
+    if turnout < rules.quorum as u128 {
+        return Outcome::QuorumNotReached;
+    }
+
+    let Some((top_index, top)) = options.iter().enumerate().max_by_key(|(_, option)| option.weighted) else {
+        return Outcome::Rejected;
+    };
+    let top_weight: u128 = top.weighted as u128;
+
+    let selected: bool = match rules.threshold {
+        Threshold::SimpleMajority => {
+            top_weight > 0
+                && options
+                    .iter()
+                    .enumerate()
+                    .all(|(index, option)| index == top_index || (option.weighted as u128) < top_weight)
+        }
+        Threshold::Supermajority { numerator, denominator } => {
+            top_weight > 0 && top_weight * denominator as u128 >= cast * numerator as u128
+        }
+    };
+
+    if selected {
+        Outcome::Winner(top_index as u32)
+    } else {
+        Outcome::Rejected
+    }
+}
+
+/// Returns a counting round with nothing counted yet.
+fn empty_round(option_count: usize) -> TallyRound {
+    TallyRound {
+        counts: vec![0; option_count],
+        exhausted: 0,
+        eliminated: vec![],
+    }
+}
+
+/// Whether option `index` was eliminated in any of `rounds`.
+fn eliminated(rounds: &[TallyRound], index: usize) -> bool {
+    rounds.iter().any(|round| round.eliminated.contains(&(index as u32)))
+}
+
+/// Returns the option with the highest count among `candidates`, ties going
+/// to the option listed first.
+fn top_option(counts: &[u64], candidates: impl Iterator<Item = usize>) -> Option<usize> {
+    candidates.fold(None, |best, index| match best {
+        Some(best) if counts[best] >= counts[index] => Some(best),
+        _ => Some(index),
+    })
+}
+
+/// The two options left after the scoring round of a STAR count, the one
+/// that led it first; ties go to the option listed first.
+fn star_finalists(scoring: &TallyRound) -> (usize, usize) {
+    let finalists: Vec<usize> = (0..scoring.counts.len())
+        .filter(|index| !scoring.eliminated.contains(&(*index as u32)))
+        .collect();
+    let (a, b) = (finalists[0], finalists[1]);
+    match scoring.counts[b] > scoring.counts[a] {
+        true => (b, a),
+        false => (a, b),
+    }
+}
+
+/// Counts a `(weight, choice)` ballot in the round being counted, the last
+/// of `rounds`. Ballots of other kinds and `Pass` ballots are not counted.
+///
+/// Each instant-runoff round counts the ballot for its highest-ranked option
+/// still in the race. An approval count has a single round adding the
+/// weight to every approved option. The scoring round of a STAR count sums
+/// the weighted scores of every option, and its runoff counts the ballot for
+/// the finalist it scored higher; ballots scoring both equally count as
+/// exhausted.
+fn count_in_round(kind: &ProposalKind, rounds: &mut [TallyRound], choice: &Choice, weight: u64) {
+    let Some((round, earlier)) = rounds.split_last_mut() else {
+        return;
+    };
+
+    match (kind, choice) {
+        (ProposalKind::RankedChoice(_), Choice::Ranking(ranking)) => {
+            match ranking.iter().find(|index| !eliminated(earlier, **index as usize)) {
+                Some(index) => round.counts[*index as usize] = round.counts[*index as usize].saturating_add(weight),
+                None => round.exhausted = round.exhausted.saturating_add(weight),
+            }
+        }
+        (ProposalKind::Approval(_), Choice::Approvals(approved)) => {
+            for index in approved {
+                round.counts[*index as usize] = round.counts[*index as usize].saturating_add(weight);
+            }
+        }
+        (ProposalKind::Star(_), Choice::Scores(scores)) => match earlier.first() {
+            None => {
+                for (index, score) in scores.iter().enumerate() {
+                    round.counts[index] = round.counts[index].saturating_add(weight.saturating_mul(*score as u64));
+                }
+            }
+            Some(scoring) => {
+                let (first, second) = star_finalists(scoring);
+                match scores[first].cmp(&scores[second]) {
+                    std::cmp::Ordering::Greater => round.counts[first] = round.counts[first].saturating_add(weight),
+                    std::cmp::Ordering::Less => round.counts[second] = round.counts[second].saturating_add(weight),
+                    std::cmp::Ordering::Equal => round.exhausted = round.exhausted.saturating_add(weight),
+                }
+            }
+        },
+        _ => {}
+    }
+}
+
+/// Ends the round being counted, the last of `rounds`. Returns the winner,
+/// if any, once the count is over, or `None` after starting the next round.
+///
+/// In an instant-runoff count an option holding more than half of the
+/// counted weight, or the last option left, wins. Otherwise the option with
+/// the least weight is eliminated; ties go to the option with fewer
+/// first-preference votes, and then to the option listed last. An approval
+/// count is won by the option with the most approving weight, ties going to
+/// the option listed first. The scoring round of a STAR count keeps the two
+/// highest totals, ties going to the option listed first, and the finalist
+/// preferred by more weight wins the runoff; a tie goes to the finalist that
+/// led the scoring round.
+fn end_round(kind: &ProposalKind, rounds: &mut Vec<TallyRound>) -> Option<Option<u32>> {
+    let option_count: usize = rounds[0].counts.len();
+    let last: usize = rounds.len() - 1;
+
+    match kind {
+        ProposalKind::RankedChoice(_) => {
+            let counts: &[u64] = &rounds[last].counts;
+            let first_preferences: &[u64] = &rounds[0].counts;
+            let remaining: Vec<usize> = (0..option_count).filter(|index| !eliminated(rounds, *index)).collect();
+            let counted: u128 = remaining.iter().map(|index| counts[*index] as u128).sum();
+            let leader: Option<usize> = remaining.iter().copied().max_by_key(|index| counts[*index]);
+
+            match leader {
+                _ if counted == 0 => return Some(None),
+                Some(leader) if counts[leader] as u128 * 2 > counted || remaining.len() == 1 => {
+                    return Some(Some(leader as u32))
+                }
+                _ => {}
+            }
+
+            let loser: usize = remaining
+                .iter()
+                .copied()
+                .min_by(|a, b| {
+                    counts[*a]
+                        .cmp(&counts[*b])
+                        .then(first_preferences[*a].cmp(&first_preferences[*b]))
+                        .then(b.cmp(a))
+                })
+                .expect("at least two options remain");
+            rounds[last].eliminated.push(loser as u32);
+            rounds.push(empty_round(option_count));
+            None
+        }
+        ProposalKind::Approval(_) => {
+            let counts: &[u64] = &rounds[0].counts;
+            let winner: Option<usize> = top_option(counts, 0..option_count).filter(|index| counts[*index] > 0);
+            Some(winner.map(|index| index as u32))
+        }
+        ProposalKind::Star(_) if last == 0 => {
+            let totals: &[u64] = &rounds[0].counts;
+            let first: Option<usize> = top_option(totals, 0..option_count).filter(|index| totals[*index] > 0);
+            let second: Option<usize> = top_option(totals, (0..option_count).filter(|index| Some(*index) != first));
+            let (Some(first), Some(second)) = (first, second) else {
+                return Some(None);
+            };
+
+            rounds[0].eliminated = (0..option_count as u32)
+                .filter(|index| *index as usize != first && *index as usize != second)
+                .collect();
+            rounds.push(empty_round(option_count));
+            None
+        }
+        ProposalKind::Star(_) => {
+            let (first, second) = star_finalists(&rounds[0]);
+            let runoff: &mut TallyRound = &mut rounds[last];
+            let (winner, runner_up) = match runoff.counts[second] > runoff.counts[first] {
+                true => (second, first),
+                false => (first, second),
+            };
+            runoff.eliminated = vec![runner_up as u32];
+            Some(Some(winner as u32))
+        }
+        _ => Some(None),
+    }
+}
+
+/// Counts up to TALLY_BATCH_SIZE ballots after `cursor` in the round being
+/// counted, adding the weight of those other than `Pass` to `cast` in the
+/// first round. Returns the last voter counted, or `None` once every ballot
+/// of the round is counted.
+fn count_ballots(
+    key: u64,
+    kind: &ProposalKind,
+    rounds: &mut [TallyRound],
+    cast: &mut u64,
+    cursor: Option<candid::Principal>,
+) -> Option<candid::Principal> {
+    let start = match cursor {
+        Some(principal) => Bound::Excluded((key, StablePrincipal(principal))),
+        None => Bound::Included((key, StablePrincipal(candid::Principal::management_canister()))),
+    };
+    let ballots: Vec<(StablePrincipal, Ballot)> = BALLOTS.with(|b| {
+        b.borrow()
+            .range((start, Bound::Unbounded))
+            .take_while(|((id, _), _)| *id == key)
+            .take(TALLY_BATCH_SIZE)
+            .map(|((_, voter), ballot)| (voter, ballot))
+            .collect()
+    });
+
+    for (_, ballot) in &ballots {
+        if rounds.len() == 1 && !matches!(ballot.choice, Choice::Pass) {
+            *cast = cast.saturating_add(ballot.weight);
+        }
+        count_in_round(kind, rounds, &ballot.choice, ballot.weight);
+    }
+
+    match ballots.len() < TALLY_BATCH_SIZE {
+        true => None,
+        false => ballots.last().map(|(voter, _)| voter.0),
+    }
+}
+
+/// Whether the outcome of proposals of this kind is counted from the
+/// individual ballots over one or more stored rounds when they close.
+fn counted_in_rounds(kind: &ProposalKind) -> bool {
+    matches!(
+        kind,
+        ProposalKind::RankedChoice(_) | ProposalKind::Approval(_) | ProposalKind::Star(_)
+    )
+}
+
+/// Outcome of a proposal counted in rounds, from the winner of the count and
+/// the weight `cast` on ballots other than `Pass`.
+fn counted_outcome(proposal: &Proposal, winner: Option<u32>, cast: u64) -> Outcome {
+    let pass: u64 = if proposal.rules.pass_counts_toward_quorum { proposal.weighted.pass } else { 0 };
+    match winner {
+        _ if cast.saturating_add(pass) < proposal.rules.quorum => Outcome::QuorumNotReached,
+        Some(index) => Outcome::Winner(index),
+        None => Outcome::Rejected,
+    }
+}
+
+/// Provisional outcome from the live tallies. Proposals counted in rounds
+/// are only fully counted when they close, so their option tallies stand in
+/// for them.
+fn tally_outcome(proposal: &Proposal) -> Outcome {
+    match proposal.kind {
+        ProposalKind::YesNo => decide(&proposal.rules, &proposal.weighted),
+        ProposalKind::Conviction(_) => match conviction_passes_at(proposal).is_some_and(|at| at <= time()) {
+            true => Outcome::Passed,
+            false => Outcome::Rejected,
+        },
+        ProposalKind::Optimistic { veto_threshold } => match proposal.weighted.reject >= veto_threshold {
+            true => Outcome::Rejected,
+            false => Outcome::Passed,
+        },
+        _ => decide_options(&proposal.rules, &proposal.option_tallies, proposal.weighted.pass),
+    }
+}
+
+/// Returns the outcome of an open proposal if no remaining voter of its
+/// electorate can change it any more. Proposals accepting ballot changes are
+/// never settled early, as voters who already voted may still change it.
+fn settled_outcome(proposal: &Proposal) -> Option<Outcome> {
+    if proposal.allow_vote_changes {
+        return None;
+    }
+
+    match proposal.kind {
+        ProposalKind::YesNo => {}
+        // Further ballots can only add vetoes, so the proposal is rejected
+        // as soon as they reach the threshold.
+        ProposalKind::Optimistic { veto_threshold } => {
+            return (proposal.weighted.reject >= veto_threshold).then_some(Outcome::Rejected);
+        }
+        _ => return None,
+    }
+
+    let electorate: u64 = proposal.electorate?.total_weight;
+    let remaining: u64 = electorate.saturating_sub(proposal.weighted.total());
+
+    // More approvals only help a proposal pass and more rejections only hurt
+    // it, so the result is settled when both extremes agree with the current
+    // one. The current tally is also the worst case for reaching quorum.
+    let current: Outcome = tally_outcome(proposal);
+    let mut all_approve: Tally = proposal.weighted;
+    all_approve.add(&Choice::Approve, remaining);
+    let mut all_reject: Tally = proposal.weighted;
+    all_reject.add(&Choice::Reject, remaining);
+
+    let (all_approve, all_reject) = (decide(&proposal.rules, &all_approve), decide(&proposal.rules, &all_reject));
+
+    (current == all_approve && current == all_reject).then_some(current)
+}
+
+/// Pushes the deadline back when a ballot cast at `now` within the
+/// wait-for-quiet window changed the provisional outcome from `before`. Each
+/// extension is capped so they add up to at most `max_extension`. Returns the
+/// new deadline if it moved.
+fn extend_deadline(proposal: &mut Proposal, before: Outcome, now: u64) -> Option<u64> {
+    let (Some(wfq), Some(ends_at)) = (&proposal.wait_for_quiet, proposal.voting_ends_at) else {
+        return None;
+    };
+
+    if tally_outcome(proposal) == before || ends_at.saturating_sub(now) > wfq.window {
+        return None;
+    }
+
+    let extension: u64 = wfq
+        .extension
+        .min(wfq.max_extension.saturating_sub(proposal.deadline_extended_by));
+    if extension == 0 {
+        return None;
+    }
+
+    let new_ends_at: u64 = ends_at.saturating_add(extension);
+    proposal.voting_ends_at = Some(new_ends_at);
+    proposal.deadline_extended_by += extension;
+    proposal.history.push(HistoryEntry {
+        at: now,
+        event: ProposalEvent::DeadlineExtended { from: ends_at, to: new_ends_at },
+    });
+    Some(new_ends_at)
+}
+
+/// Applies wait-for-quiet to a proposal after a ballot (see
+/// `extend_deadline`) and moves its deadline timer along.
+fn wait_for_quiet(key: u64, proposal: &mut Proposal, before: Outcome) {
+    if let Some(ends_at) = extend_deadline(proposal, before, time()) {
+        schedule_deadline(key, ends_at);
+    }
+}
+
+/// Adds `principal` with `weight` to the electorate of proposal `key` if
+/// they hold the Voter role, as `check_can_vote` trusts the snapshot.
+fn add_to_electorate(key: u64, summary: &mut Electorate, principal: StablePrincipal, weight: u64) {
+    if !has_role(principal.0, Role::Voter) {
+        return;
+    }
+    ELECTORATE.with(|e| e.borrow_mut().insert((key, principal), weight));
+    summary.size += 1;
+    summary.total_weight = summary.total_weight.saturating_add(weight);
+}
+
+/// Copies the jurors of a jury proposal with their current weight into its
+/// electorate.
+fn snapshot_jury(key: u64, jury: &Jury) -> Electorate {
+    let mut summary: Electorate = Electorate { size: 0, total_weight: 0 };
+    for juror in &jury.jurors {
+        if let Some(member) = MEMBERS.with(|m| m.borrow().get(&StablePrincipal(*juror))) {
+            add_to_electorate(key, &mut summary, StablePrincipal(*juror), member.weight);
+        }
+    }
+    summary
+}
+
+/// Copies the members after `cursor` with their current weight into the
+/// electorate of a new proposal. Stops after SNAPSHOT_BATCH_SIZE members and
+/// returns the last one, or `None` once the whole registry is copied.
+fn snapshot_members(key: u64, proposal: &mut Proposal, cursor: Option<candid::Principal>) -> Option<candid::Principal> {
+    let start = match cursor {
+        Some(principal) => Bound::Excluded(StablePrincipal(principal)),
+        None => Bound::Unbounded,
+    };
+    let members: Vec<(StablePrincipal, Member)> = MEMBERS.with(|m| {
+        m.borrow()
+            .range((start, Bound::Unbounded))
+            .take(SNAPSHOT_BATCH_SIZE)
+            .collect()
+    });
+
+    let complete: bool = members.len() < SNAPSHOT_BATCH_SIZE;
+    let mut summary: Electorate = proposal.electorate.unwrap_or(Electorate { size: 0, total_weight: 0 });
+    let mut last: Option<candid::Principal> = None;
+    for (principal, member) in members {
+        last = Some(principal.0);
+        add_to_electorate(key, &mut summary, principal, member.weight);
+    }
+    proposal.electorate = Some(summary);
+
+    match complete {
+        true => None,
+        false => last,
+    }
+}
+
+/// Returns the weight `voter` votes with on `proposal`.
+fn voting_weight(key: u64, proposal: &Proposal, voter: candid::Principal) -> Result<u64, VoteError> {
+    let voter: StablePrincipal = StablePrincipal(voter);
+
+    match (proposal.electorate, proposal.ledger) {
+        (Some(_), _) => ELECTORATE
+            .with(|e| e.borrow().get(&(key, voter)))
+            .ok_or(VoteError::NotAMember),
+        (None, Some(_)) => match ELECTORATE.with(|e| e.borrow().get(&(key, voter))) {
+            Some(0) | None => Err(VoteError::NoVotingPower),
+            Some(escrowed) => Ok(escrowed),
+        },
+        // Open electorate: members vote with their weight, anyone else with 1.
+        (None, None) => Ok(MEMBERS.with(|m| m.borrow().get(&voter)).map_or(1, |member| member.weight)),
+    }
+}
+
+/// Subaccount of this canister holding the tokens escrowed on proposal
+/// `key`: the key in big-endian order in the last 8 of its 32 bytes.
+fn escrow_subaccount(key: u64) -> Vec<u8> {
+    let mut subaccount: Vec<u8> = vec![0; 32];
+    subaccount[24..].copy_from_slice(&key.to_be_bytes());
+    subaccount
+}
+
+/// Maps the result of a ledger transfer to the block index it was recorded
+/// at.
+fn transfer_result(result: CallResult<(Result<candid::Nat, LedgerError>,)>) -> Result<candid::Nat, VoteError> {
+    match result {
+        Ok((Ok(block),)) => Ok(block),
+        Ok((Err(error),)) => Err(VoteError::LedgerCallFailed(format!("{:?}", error))),
+        Err((code, message)) => Err(VoteError::LedgerCallFailed(format!("{:?}: {}", code, message))),
+    }
+}
+
+fn transition(proposal: &mut Proposal, to: ProposalStatus) -> Result<(), VoteError> {
+    let allowed: bool = match (proposal.status, to) {
+        (ProposalStatus::Draft, ProposalStatus::Open)
+        | (ProposalStatus::Draft, ProposalStatus::Cancelled)
+        | (ProposalStatus::Open, ProposalStatus::Closed)
+        | (ProposalStatus::Open, ProposalStatus::Cancelled)
+        | (ProposalStatus::Revealing, ProposalStatus::Closed)
+        | (ProposalStatus::Revealing, ProposalStatus::Cancelled)
+        | (ProposalStatus::Open, ProposalStatus::Tallying)
+        | (ProposalStatus::Revealing, ProposalStatus::Tallying)
+        | (ProposalStatus::Tallying, ProposalStatus::Closed) => true,
+        (ProposalStatus::Open, ProposalStatus::Revealing) => proposal.secret_ballot.is_some(),
+        (ProposalStatus::Closed, ProposalStatus::Executed) => {
+            matches!(proposal.outcome, Some(Outcome::Passed | Outcome::Winner(_)))
+        }
+        _ => false,
+    };
+
+    if !allowed {
+        return Err(VoteError::InvalidTransition { from: proposal.status, to });
+    }
+
+    proposal.status = to;
+    proposal.history.push(HistoryEntry {
+        at: time(),
+        event: ProposalEvent::StatusChanged(to),
+    });
+    Ok(())
+}
+
+fn open_proposal_now(key: u64, proposal: &mut Proposal) -> Result<(), VoteError> {
+    if PENDING_SNAPSHOTS.with(|s| s.borrow().contains_key(&key)) {
+        return Err(VoteError::SnapshotInProgress);
+    }
+    let voting_ends_at: Option<u64> = voting_deadline(&proposal.voting_period)?;
+    transition(proposal, ProposalStatus::Open)?;
+    proposal.voting_ends_at = voting_ends_at;
+
+    if let Some(ends_at) = voting_ends_at {
+        schedule_deadline(key, ends_at);
+    }
+    Ok(())
+}
+
+/// Ends voting on an open proposal: secret-ballot proposals move on to their
+/// reveal window, the others close (see `close_proposal`). Ending the reveal
+/// window closes them.
+fn end_voting(key: u64, proposal: &mut Proposal) -> Result<(), VoteError> {
+    match (proposal.status, &proposal.secret_ballot) {
+        (ProposalStatus::Open, Some(secret)) => {
+            let reveal_ends_at: u64 = time().saturating_add(secret.reveal_period);
+            transition(proposal, ProposalStatus::Revealing)?;
+            proposal.reveal_ends_at = Some(reveal_ends_at);
+            schedule_deadline(key, reveal_ends_at);
+        }
+        _ => {
+            close_proposal(key, proposal)?;
+            cancel_deadline(key);
+        }
+    }
+    Ok(())
+}
+
+/// Deadline of the current phase of a proposal, if it has one. Open
+/// conviction proposals pass when their conviction reaches the threshold.
+fn phase_deadline(proposal: &Proposal) -> Option<u64> {
+    match proposal.status {
+        ProposalStatus::Open => match proposal.kind {
+            ProposalKind::Conviction(_) => conviction_passes_at(proposal),
+            _ => proposal.voting_ends_at,
+        },
+        ProposalStatus::Revealing => proposal.reveal_ends_at,
+        _ => None,
+    }
+}
+
+/// Ends voting on a proposal. Casting delegated ballots and counting in
+/// rounds go over the whole electorate or every ballot, so proposals needing
+/// either move to `Tallying` and do it in batches on a timer before they
+/// close; the others close right away.
+fn close_proposal(key: u64, proposal: &mut Proposal) -> Result<(), VoteError> {
+    let stage: TallyStage = match (delegations_apply(proposal), counted_in_rounds(&proposal.kind)) {
+        (true, _) => TallyStage::Delegations,
+        (false, true) => counting_stage(proposal),
+        (false, false) => {
+            let outcome: Outcome = tally_outcome(proposal);
+            return decide_proposal(proposal, outcome);
+        }
+    };
+
+    transition(proposal, ProposalStatus::Tallying)?;
+    PENDING_TALLIES.with(|t| t.borrow_mut().insert(key, PendingTally { stage, cursor: None }));
+    schedule_tally(key);
+    Ok(())
+}
+
+fn counting_stage(proposal: &Proposal) -> TallyStage {
+    TallyStage::Counting {
+        rounds: vec![empty_round(proposal.option_tallies.len())],
+        cast: 0,
+    }
+}
+
+/// Closes a proposal whose ballots are all counted with `outcome`.
+fn decide_proposal(proposal: &mut Proposal, outcome: Outcome) -> Result<(), VoteError> {
+    transition(proposal, ProposalStatus::Closed)?;
+    proposal.outcome = Some(outcome);
+    Ok(())
+}
+
+/// Loads a proposal and checks that the caller owns it.
+fn owned_proposal(key: u64) -> Result<Proposal, VoteError> {
+    let proposal: Proposal = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .ok_or(VoteError::NoSuchProposal)?;
+
+    if caller() != proposal.owner {
+        return Err(VoteError::AccessRejected);
+    };
+
+    Ok(proposal)
+}
+
+/// Loads a proposal and checks that the caller owns it or moderates.
+fn moderated_proposal(key: u64) -> Result<Proposal, VoteError> {
+    let proposal: Proposal = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .ok_or(VoteError::NoSuchProposal)?;
+
+    if caller() != proposal.owner && !has_role(caller(), Role::Moderator) {
+        return Err(VoteError::AccessRejected);
+    };
+
+    Ok(proposal)
+}
+
+fn store_proposal(key: u64, proposal: Proposal) {
+    PROPOSAL_MAP.with(|p| p.borrow_mut().insert(key, proposal));
+}
+
+fn schedule_deadline(key: u64, ends_at: u64) {
+    let delay = Duration::from_nanos(ends_at.saturating_sub(time()));
+    let timer_id: TimerId = ic_cdk_timers::set_timer(delay, move || end_expired_phase(key));
+
+    if let Some(old_timer) = DEADLINE_TIMERS.with(|t| t.borrow_mut().insert(key, timer_id)) {
+        ic_cdk_timers::clear_timer(old_timer);
+    }
+}
+
+fn schedule_snapshot(key: u64) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || snapshot_next_batch(key));
+}
+
+/// Copies the next batch of members into the electorate of a draft proposal,
+/// and opens it once the registry is done if it was created to open.
+fn snapshot_next_batch(key: u64) {
+    let Some(pending) = PENDING_SNAPSHOTS.with(|s| s.borrow().get(&key)) else {
+        return;
+    };
+    let Some(mut proposal) = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .filter(|proposal| proposal.status == ProposalStatus::Draft)
+    else {
+        PENDING_SNAPSHOTS.with(|s| s.borrow_mut().remove(&key));
+        return;
+    };
+
+    match snapshot_members(key, &mut proposal, Some(pending.cursor)) {
+        Some(cursor) => {
+            PENDING_SNAPSHOTS.with(|s| s.borrow_mut().insert(key, PendingSnapshot { cursor, ..pending }));
+            schedule_snapshot(key);
+        }
+        None => {
+            PENDING_SNAPSHOTS.with(|s| s.borrow_mut().remove(&key));
+            // A deadline that passed in the meantime leaves the proposal in
+            // Draft for its owner to edit.
+            if pending.open {
+                let _ = open_proposal_now(key, &mut proposal);
+            }
+        }
+    }
+    store_proposal(key, proposal);
+}
+
+fn schedule_tally(key: u64) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || tally_next_batch(key));
+}
+
+/// Runs the next batch of the tally of a proposal in `Tallying`, and closes
+/// it once its ballots are all counted.
+fn tally_next_batch(key: u64) {
+    let Some(mut pending) = PENDING_TALLIES.with(|t| t.borrow().get(&key)) else {
+        return;
+    };
+    let Some(mut proposal) = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .filter(|proposal| proposal.status == ProposalStatus::Tallying)
+    else {
+        PENDING_TALLIES.with(|t| t.borrow_mut().remove(&key));
+        return;
+    };
+
+    let outcome: Option<Outcome> = match &mut pending.stage {
+        TallyStage::Delegations => {
+            pending.cursor = cast_delegated_ballots(key, &mut proposal, pending.cursor);
+            match (pending.cursor, counted_in_rounds(&proposal.kind)) {
+                (Some(_), _) => None,
+                (None, true) => {
+                    pending.stage = counting_stage(&proposal);
+                    None
+                }
+                (None, false) => Some(tally_outcome(&proposal)),
+            }
+        }
+        TallyStage::Counting { rounds, cast } => {
+            pending.cursor = count_ballots(key, &proposal.kind, rounds, cast, pending.cursor);
+            match pending.cursor.is_none().then(|| end_round(&proposal.kind, rounds)).flatten() {
+                Some(winner) => {
+                    let outcome: Outcome = counted_outcome(&proposal, winner, *cast);
+                    let rounds: Vec<TallyRound> = std::mem::take(rounds);
+                    TALLY_ROUNDS.with(|r| r.borrow_mut().insert(key, TallyRounds { rounds }));
+                    Some(outcome)
+                }
+                None => None,
+            }
+        }
+    };
+
+    match outcome {
+        Some(outcome) => {
+            PENDING_TALLIES.with(|t| t.borrow_mut().remove(&key));
+            if decide_proposal(&mut proposal, outcome).is_err() {
+                return;
+            }
+        }
+        None => {
+            PENDING_TALLIES.with(|t| t.borrow_mut().insert(key, pending));
+            schedule_tally(key);
+        }
+    }
+    store_proposal(key, proposal);
+}
+
+fn cancel_deadline(key: u64) {
+    if let Some(timer_id) = DEADLINE_TIMERS.with(|t| t.borrow_mut().remove(&key)) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+}
+
+fn end_expired_phase(key: u64) {
+    DEADLINE_TIMERS.with(|t| t.borrow_mut().remove(&key));
+
+    PROPOSAL_MAP.with(|p| {
+        let Some(mut proposal) = p.borrow().get(&key) else {
+            return;
+        };
+
+        if phase_deadline(&proposal).is_some_and(|ends_at| ends_at <= time())
+            && end_voting(key, &mut proposal).is_ok()
+        {
+            p.borrow_mut().insert(key, proposal);
+        }
+    })
+}
+
+fn with_config<R>(f: impl FnOnce(&Config) -> R) -> R {
+    CONFIG.with(|c| f(c.borrow().get()))
+}
+
+fn update_config(f: impl FnOnce(&mut Config)) -> Result<(), VoteError> {
+    CONFIG.with(|c| {
+        let mut cell = c.borrow_mut();
+        let mut config: Config = cell.get().clone();
+        f(&mut config);
+        cell.set(config)
+            .map(|_| ())
+            .map_err(|e| VoteError::UpdateError(format!("Cannot update config: {:?}", e)))
+    })
+}
+
+/// Returns the delegation `principal` set for `topic`, or their default
+/// delegation when `topic` is `None`.
+fn delegate_of(principal: candid::Principal, topic: Option<Topic>) -> Option<candid::Principal> {
+    let delegate: Option<StablePrincipal> = match topic {
+        Some(topic) => TOPIC_DELEGATIONS.with(|d| d.borrow().get(&(topic.id(), StablePrincipal(principal)))),
+        None => DELEGATIONS.with(|d| d.borrow().get(&StablePrincipal(principal))),
+    };
+    delegate.map(|delegate| delegate.0)
+}
+
+/// Returns the delegate `principal` follows on proposals about `topic`:
+/// their delegate for the topic, or else their default delegate.
+fn delegate_on(principal: candid::Principal, topic: Topic) -> Option<candid::Principal> {
+    delegate_of(principal, Some(topic)).or_else(|| delegate_of(principal, None))
+}
+
+/// Whether following the delegations on `topic` from `start` reaches
+/// `target`. Chains never loop, so the walk always ends.
+fn delegation_reaches(start: candid::Principal, target: candid::Principal, topic: Topic) -> bool {
+    let mut current: Option<candid::Principal> = Some(start);
+    while let Some(principal) = current {
+        if principal == target {
+            return true;
+        }
+        current = delegate_on(principal, topic);
+    }
+    false
+}
+
+/// Checks that `delegator` following `to` on every topic in `topics` does
+/// not close a cycle.
+fn check_delegation_cycle(
+    delegator: candid::Principal,
+    to: candid::Principal,
+    mut topics: impl Iterator<Item = Topic>,
+) -> Result<(), VoteError> {
+    if topics.any(|topic| delegation_reaches(to, delegator, topic)) {
+        return Err(VoteError::DelegationCycle);
+    }
+    Ok(())
+}
+
+/// Topics on which `delegator` follows their default delegate.
+fn default_topics(delegator: candid::Principal) -> impl Iterator<Item = Topic> {
+    Topic::ALL
+        .into_iter()
+        .filter(move |topic| delegate_of(delegator, Some(*topic)).is_none())
+}
+
+/// Follows the delegation chain of `delegator` on `topic` to the first
+/// delegate who voted on the proposal themselves, and returns them with
+/// their ballot. Every hop is added to `work`.
+fn followed_ballot(
+    key: u64,
+    delegator: candid::Principal,
+    topic: Topic,
+    work: &mut usize,
+) -> Option<(candid::Principal, Ballot)> {
+    let mut current: candid::Principal = delegator;
+    while let Some(delegate) = delegate_on(current, topic) {
+        *work += 1;
+        let ballot: Option<Ballot> = BALLOTS.with(|b| b.borrow().get(&(key, StablePrincipal(delegate))));
+        if let Some(ballot) = ballot.filter(|ballot| ballot.via.is_none()) {
+            return Some((delegate, ballot));
+        }
+        current = delegate;
+    }
+    None
+}
+
+/// Whether delegated ballots are cast for a proposal when voting ends. Token
+/// proposals have no electorate to go through, jury proposals only count
+/// jurors and conviction proposals only count support staked in person.
+fn delegations_apply(proposal: &Proposal) -> bool {
+    proposal.electorate.is_some() && proposal.jury.is_none() && !matches!(proposal.kind, ProposalKind::Conviction(_))
+}
+
+/// Casts a ballot for every member of the electorate after `cursor` who did
+/// not vote but delegates on the proposal's topic, directly or through other
+/// delegates who did not vote either, to someone who did. Stops once about
+/// TALLY_BATCH_SIZE members and delegation hops are handled, and returns the
+/// last member handled, or `None` when the whole electorate is done.
+fn cast_delegated_ballots(
+    key: u64,
+    proposal: &mut Proposal,
+    cursor: Option<candid::Principal>,
+) -> Option<candid::Principal> {
+    let start = match cursor {
+        Some(principal) => Bound::Excluded((key, StablePrincipal(principal))),
+        None => Bound::Included((key, StablePrincipal(candid::Principal::management_canister()))),
+    };
+    let electorate: Vec<(StablePrincipal, u64)> = ELECTORATE.with(|e| {
+        e.borrow()
+            .range((start, Bound::Unbounded))
+            .take_while(|((id, _), _)| *id == key)
+            .take(TALLY_BATCH_SIZE)
+            .map(|((_, voter), weight)| (voter, weight))
+            .collect()
+    });
+
+    let complete: bool = electorate.len() < TALLY_BATCH_SIZE;
+    let mut last: Option<candid::Principal> = None;
+    let mut work: usize = 0;
+    for (delegator, weight) in electorate {
+        if work >= TALLY_BATCH_SIZE {
+            return last;
+        }
+        work += 1;
+        last = Some(delegator.0);
+
+        if weight == 0 || BALLOTS.with(|b| b.borrow().contains_key(&(key, delegator.clone()))) {
+            continue;
+        }
+        let Some((voter, followed)) = followed_ballot(key, delegator.0, proposal.topic, &mut work) else {
+            continue;
+        };
+
+        count_ballot(proposal, &followed.choice, weight);
+        let ballot: Ballot = Ballot {
+            choice: followed.choice,
+            weight,
+            cast_at: time(),
+            reason: None,
+            via: Some(voter),
+        };
+        BALLOTS.with(|b| b.borrow_mut().insert((key, delegator), ballot));
+    }
+
+    match complete {
+        true => None,
+        false => last,
+    }
+}
+
+fn is_member(principal: candid::Principal) -> bool {
+    MEMBERS.with(|m| m.borrow().contains_key(&StablePrincipal(principal)))
+}
+
+/// When `members_only` is set, requires the caller to be a member holding
+/// `role`.
+fn check_membership(members_only: bool, role: Role) -> Result<(), VoteError> {
+    if !members_only {
+        return Ok(());
+    } else if !is_member(caller()) {
+        return Err(VoteError::NotAMember);
+    } else if !has_role(caller(), role) {
+        return Err(VoteError::AccessRejected);
+    }
+    Ok(())
+}
+
+fn role_bits(principal: candid::Principal) -> u8 {
+    ROLES.with(|r| r.borrow().get(&StablePrincipal(principal)).unwrap_or(0))
+}
+
+fn has_role(principal: candid::Principal, role: Role) -> bool {
+    let bits: u8 = role_bits(principal);
+    bits & (role.bit() | Role::Admin.bit()) != 0
+}
+
+fn set_role(principal: candid::Principal, role: Role, granted: bool) {
+    let bits: u8 = match granted {
+        true => role_bits(principal) | role.bit(),
+        false => role_bits(principal) & !role.bit(),
+    };
+
+    ROLES.with(|r| match bits {
+        0 => r.borrow_mut().remove(&StablePrincipal(principal)),
+        _ => r.borrow_mut().insert(StablePrincipal(principal), bits),
+    });
+}
+
+fn roles_from_bits(bits: u8) -> Vec<Role> {
+    Role::ALL.into_iter().filter(|role| bits & role.bit() != 0).collect()
+}
+
+fn caller_is_authenticated() -> Result<(), String> {
+    if caller() == candid::Principal::anonymous() {
+        return Err("Anonymous callers are not allowed".to_string());
+    }
+    Ok(())
+}
+
+fn caller_with_role(role: Role) -> Result<(), String> {
+    caller_is_authenticated()?;
+    if !has_role(caller(), role) {
+        return Err(format!("Caller lacks the {:?} role", role));
+    }
+    Ok(())
+}
+
+fn caller_is_admin() -> Result<(), String> {
+    caller_with_role(Role::Admin)
+}
+
+fn caller_is_observer() -> Result<(), String> {
+    caller_is_authenticated()?;
+    if role_bits(caller()) == 0 {
+        return Err("Caller holds no role".to_string());
+    }
+    Ok(())
+}
+
+/// Makes the installer an admin and a member when no admin exists yet.
+fn bootstrap_admin() {
+    let has_admin: bool = ROLES.with(|r| r.borrow().iter().any(|(_, bits)| bits & Role::Admin.bit() != 0));
+    if has_admin {
+        return;
+    }
+
+    let admin = caller();
+    set_role(admin, Role::Admin, true);
+    if !is_member(admin) {
+        let member: Member = Member {
+            joined_at: time(),
+            weight: 1,
+        };
+        MEMBERS.with(|m| m.borrow_mut().insert(StablePrincipal(admin), member));
+    }
+}
+
+#[init]
+fn init() {
+    bootstrap_admin();
+}
+
+/// Converts a proposal of the first version of the canister. Its ballots
+/// counted one vote each, so the counts are both its headcount and its
+/// weighted tallies, and its rules are the defaults. Descriptions longer than
+/// MAX_DESCRIPTION_LEN are cut so the record fits with the new fields.
+fn convert_legacy_proposal(mut legacy: LegacyProposal, now: u64) -> Proposal {
+    if legacy.description.len() > MAX_DESCRIPTION_LEN {
+        let mut end: usize = MAX_DESCRIPTION_LEN;
+        while !legacy.description.is_char_boundary(end) {
+            end -= 1;
+        }
+        legacy.description.truncate(end);
+    }
+
+    let tally: Tally = Tally {
+        approve: legacy.approve.into(),
+        reject: legacy.reject.into(),
+        pass: legacy.pass.into(),
+    };
+    let mut history: Vec<HistoryEntry> = vec![
+        HistoryEntry {
+            at: now,
+            event: ProposalEvent::StatusChanged(ProposalStatus::Draft),
+        },
+        HistoryEntry {
+            at: now,
+            event: ProposalEvent::StatusChanged(ProposalStatus::Open),
+        },
+    ];
+    let mut proposal: Proposal = Proposal {
+        description: legacy.description,
+        kind: ProposalKind::YesNo,
+        topic: Topic::default(),
+        // The choices of legacy ballots were not recorded, so they cannot be
+        // taken back out of the tallies.
+        allow_vote_changes: false,
+        headcount: tally,
+        weighted: tally,
+        option_tallies: vec![],
+        status: ProposalStatus::Open,
+        owner: legacy.owner,
+        created_at: now,
+        voting_period: None,
+        voting_ends_at: None,
+        rules: DecisionRules::default(),
+        electorate: None,
+        ledger: None,
+        wait_for_quiet: None,
+        deadline_extended_by: 0,
+        secret_ballot: None,
+        reveal_ends_at: None,
+        outcome: None,
+        history: vec![],
+        blind_results: false,
+        conviction: None,
+        jury: None,
+    };
+    if !legacy.is_active {
+        history.push(HistoryEntry {
+            at: now,
+            event: ProposalEvent::StatusChanged(ProposalStatus::Closed),
+        });
+        proposal.status = ProposalStatus::Closed;
+        proposal.outcome = Some(tally_outcome(&proposal));
+    }
+    proposal.history = history;
+    proposal
+}
+
+/// Rewrites the proposals stored by the first version of the canister in the
+/// current format and moves their voters into BALLOTS. Runs before
+/// PROPOSAL_MAP is first used, since that map cannot decode them.
+fn migrate_legacy_proposals() {
+    let mut raw: StableBTreeMap<u64, RawProposal, Memory> =
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))));
+
+    let now: u64 = time();
+    let converted: Vec<(u64, Vec<candid::Principal>, Proposal)> = raw
+        .iter()
+        .filter_map(|(key, record)| {
+            let mut legacy: LegacyProposal = Decode!(&record.0, LegacyProposal).ok()?;
+            let voters: Vec<candid::Principal> = std::mem::take(&mut legacy.voted);
+            Some((key, voters, convert_legacy_proposal(legacy, now)))
+        })
+        .collect();
+
+    for (key, voters, proposal) in converted {
+        // Legacy ballots only recorded who voted, so they are kept as passes
+        // of weight 1 that block a second vote; their choice is already in
+        // the tallies.
+        BALLOTS.with(|b| {
+            let mut ballots = b.borrow_mut();
+            for voter in voters {
+                let ballot: Ballot = Ballot {
+                    choice: Choice::Pass,
+                    weight: 1,
+                    cast_at: now,
+                    reason: Some(LEGACY_BALLOT_REASON.to_string()),
+                    via: None,
+                };
+                ballots.insert((key, StablePrincipal(voter)), ballot);
+            }
+        });
+        raw.insert(key, RawProposal(Encode!(&proposal).unwrap()));
+    }
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    migrate_legacy_proposals();
+
+    // Canisters installed before roles existed have no admin yet.
+    bootstrap_admin();
+
+    let deadlines: Vec<(u64, u64)> = PROPOSAL_MAP.with(|p| {
+        p.borrow()
+            .iter()
+            .filter_map(|(key, proposal)| phase_deadline(&proposal).map(|ends_at| (key, ends_at)))
+            .collect()
+    });
+
+    for (key, ends_at) in deadlines {
+        schedule_deadline(key, ends_at);
+    }
+
+    let pending: Vec<u64> = PENDING_TALLIES.with(|t| t.borrow().iter().map(|(key, _)| key).collect());
+    for key in pending {
+        schedule_tally(key);
+    }
+
+    let snapshots: Vec<u64> = PENDING_SNAPSHOTS.with(|s| s.borrow().iter().map(|(key, _)| key).collect());
+    for key in snapshots {
+        schedule_snapshot(key);
+    }
+}
+
+#[query]
+fn get_proposal(key: u64) -> Option<ProposalView> {
+    PROPOSAL_MAP.with(|p| p.borrow().get(&key)).map(proposal_view)
+}
+
+#[query]
+fn get_proposal_count() -> u64 {
+    PROPOSAL_MAP.with(|p| p.borrow().len())
+}
+
+#[query]
+fn get_electorate(proposal_id: u64) -> Option<Electorate> {
+    PROPOSAL_MAP.with(|p| p.borrow().get(&proposal_id)).and_then(|proposal| proposal.electorate)
+}
+
+/// Returns the conviction of a conviction-voting proposal accumulated up to
+/// now, unless its tallies are withheld.
+#[query]
+fn get_conviction(proposal_id: u64) -> Option<ConvictionStatus> {
+    let mut proposal: Proposal = PROPOSAL_MAP.with(|p| p.borrow().get(&proposal_id))?;
+    let ProposalKind::Conviction(params) = &proposal.kind else {
+        return None;
+    };
+    if tallies_hidden(&proposal) {
+        return None;
+    }
+
+    let threshold: f64 = conviction_threshold(params);
+    let open: bool = proposal.status == ProposalStatus::Open;
+    let passes_at: Option<u64> = conviction_passes_at(&proposal).filter(|_| open);
+    if open && proposal.conviction.is_some() {
+        accrue_conviction(&mut proposal);
+    }
+
+    Some(ConvictionStatus {
+        conviction: proposal.conviction.map_or(0.0, |conviction| conviction.value),
+        staked: proposal.weighted.approve,
+        threshold,
+        passes_at,
+    })
+}
+
+#[query]
+fn get_tally_rounds(proposal_id: u64) -> Option<TallyRounds> {
+    TALLY_ROUNDS.with(|r| r.borrow().get(&proposal_id))
+}
+
+/// Returns the credits `voter` has spent on a quadratic proposal, or `None`
+/// for other proposals. While the tallies are withheld voters only see their
+/// own.
+#[query]
+fn get_spent_credits(proposal_id: u64, voter: candid::Principal) -> Option<u64> {
+    let proposal: Proposal = PROPOSAL_MAP.with(|p| p.borrow().get(&proposal_id))?;
+    if tallies_hidden(&proposal) && voter != caller() {
+        return None;
+    }
+
+    match proposal.kind {
+        ProposalKind::Quadratic { .. } => {
+            Some(CREDITS.with(|c| c.borrow().get(&(proposal_id, StablePrincipal(voter)))).unwrap_or(0))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the ballots `voter` changed or retracted on a proposal, oldest
+/// first. While the tallies are withheld voters only see their own.
+#[query]
+fn get_ballot_history(proposal_id: u64, voter: candid::Principal) -> Vec<PastBallot> {
+    let hidden: bool = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&proposal_id))
+        .is_some_and(|proposal| tallies_hidden(&proposal));
+    if hidden && voter != caller() {
+        return vec![];
+    }
+
+    BALLOT_HISTORY
+        .with(|h| h.borrow().get(&(proposal_id, StablePrincipal(voter))))
+        .map_or_else(Vec::new, |history| history.ballots)
+}
+
+#[query]
+fn get_my_ballot(proposal_id: u64) -> Option<Ballot> {
+    BALLOTS.with(|b| b.borrow().get(&(proposal_id, StablePrincipal(caller()))))
+}
+
+/// Returns up to `limit` ballots of a proposal ordered by voter, starting
+/// after `cursor` (the last voter of the previous page).
+#[query]
+fn list_ballots(proposal_id: u64, cursor: Option<candid::Principal>, limit: u32) -> Vec<BallotEntry> {
+    if PROPOSAL_MAP.with(|p| p.borrow().get(&proposal_id)).is_some_and(|proposal| tallies_hidden(&proposal)) {
+        return vec![];
+    }
+
+    let start = match cursor {
+        Some(voter) => Bound::Excluded((proposal_id, StablePrincipal(voter))),
+        None => Bound::Included((proposal_id, StablePrincipal(candid::Principal::management_canister()))),
+    };
+
+    BALLOTS.with(|b| {
+        b.borrow()
+            .range((start, Bound::Unbounded))
+            .take_while(|((id, _), _)| *id == proposal_id)
+            .take(limit.min(MAX_BALLOT_PAGE) as usize)
+            .map(|((_, voter), ballot)| BallotEntry { voter: voter.0, ballot })
+            .collect()
+    })
+}
+
+/// Returns up to `limit` commitments of a closed secret-ballot proposal
+/// ordered by voter, starting after `cursor` (the last voter of the previous
+/// page), so unrevealed ones can be audited. Nothing is listed before the
+/// proposal closes.
+#[query]
+fn list_commitments(proposal_id: u64, cursor: Option<candid::Principal>, limit: u32) -> Vec<CommitmentEntry> {
+    if PROPOSAL_MAP.with(|p| p.borrow().get(&proposal_id)).is_some_and(|proposal| tallies_hidden(&proposal)) {
+        return vec![];
+    }
+
+    let start = match cursor {
+        Some(voter) => Bound::Excluded((proposal_id, StablePrincipal(voter))),
+        None => Bound::Included((proposal_id, StablePrincipal(candid::Principal::management_canister()))),
+    };
+
+    COMMITMENTS.with(|c| {
+        c.borrow()
+            .range((start, Bound::Unbounded))
+            .take_while(|((id, _), _)| *id == proposal_id)
+            .take(limit.min(MAX_BALLOT_PAGE) as usize)
+            .map(|((_, voter), commitment)| CommitmentEntry {
+                revealed: BALLOTS.with(|b| b.borrow().contains_key(&(proposal_id, voter.clone()))),
+                voter: voter.0,
+                commitment,
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn get_config() -> Config {
+    with_config(Config::clone)
+}
+
+#[update(guard = "caller_is_admin")]
+fn set_gating(members_only_create: bool, members_only_vote: bool) -> Result<(), VoteError> {
+    update_config(|c| {
+        c.members_only_create = members_only_create;
+        c.members_only_vote = members_only_vote;
+    })
+}
+
+#[query(guard = "caller_is_observer")]
+fn get_roles(principal: candid::Principal) -> Vec<Role> {
+    roles_from_bits(role_bits(principal))
+}
+
+/// Returns up to `limit` principals holding a role, ordered by principal,
+/// starting after `cursor` (the last principal of the previous page).
+#[query(guard = "caller_is_observer")]
+fn list_roles(cursor: Option<candid::Principal>, limit: u32) -> Vec<RoleEntry> {
+    let start = match cursor {
+        Some(principal) => Bound::Excluded(StablePrincipal(principal)),
+        None => Bound::Unbounded,
+    };
+
+    ROLES.with(|r| {
+        r.borrow()
+            .range((start, Bound::Unbounded))
+            .take(limit.min(MAX_MEMBER_PAGE) as usize)
+            .map(|(principal, bits)| RoleEntry {
+                principal: principal.0,
+                roles: roles_from_bits(bits),
+            })
+            .collect()
+    })
+}
+
+#[update(guard = "caller_is_admin")]
+fn grant_role(principal: candid::Principal, role: Role) -> Result<(), VoteError> {
+    if principal == candid::Principal::anonymous() {
+        return Err(VoteError::AccessRejected);
+    }
+
+    set_role(principal, role, true);
+    Ok(())
+}
+
+#[update(guard = "caller_is_admin")]
+fn revoke_role(principal: candid::Principal, role: Role) -> Result<(), VoteError> {
+    // Admins cannot demote themselves, so at least one admin always remains.
+    if principal == caller() && role == Role::Admin {
+        return Err(VoteError::AccessRejected);
+    }
+
+    set_role(principal, role, false);
+    Ok(())
+}
+
+/// Registers a member with the given voting weight and grants them the
+/// Voter role.
+#[update(guard = "caller_is_admin")]
+fn add_member(principal: candid::Principal, weight: u64) -> Result<(), VoteError> {
+    if principal == candid::Principal::anonymous() {
+        return Err(VoteError::AccessRejected);
+    } else if weight == 0 {
+        return Err(VoteError::InvalidWeight);
+    } else if is_member(principal) {
+        return Err(VoteError::AlreadyAMember);
+    }
+
+    let member: Member = Member {
+        joined_at: time(),
+        weight,
+    };
+    MEMBERS.with(|m| m.borrow_mut().insert(StablePrincipal(principal), member));
+    set_role(principal, Role::Voter, true);
+    Ok(())
+}
+
+#[update(guard = "caller_is_admin")]
+fn set_member_weight(principal: candid::Principal, weight: u64) -> Result<(), VoteError> {
+    if weight == 0 {
+        return Err(VoteError::InvalidWeight);
+    }
+
+    MEMBERS.with(|m| {
+        let mut members = m.borrow_mut();
+        let member: Member = members.get(&StablePrincipal(principal)).ok_or(VoteError::NotAMember)?;
+        members.insert(StablePrincipal(principal), Member { weight, ..member });
+        Ok(())
+    })
+}
+
+/// Removes a member and revokes their Voter role.
+#[update(guard = "caller_is_admin")]
+fn remove_member(principal: candid::Principal) -> Result<(), VoteError> {
+    MEMBERS
+        .with(|m| m.borrow_mut().remove(&StablePrincipal(principal)))
+        .ok_or(VoteError::NotAMember)?;
+
+    set_role(principal, Role::Voter, false);
+    Ok(())
+}
+
+/// Returns up to `limit` members ordered by principal, starting after
+/// `cursor` (the last member of the previous page).
+#[query(guard = "caller_is_observer")]
+fn list_members(cursor: Option<candid::Principal>, limit: u32) -> Vec<MemberEntry> {
+    let start = match cursor {
+        Some(principal) => Bound::Excluded(StablePrincipal(principal)),
+        None => Bound::Unbounded,
+    };
+
+    MEMBERS.with(|m| {
+        m.borrow()
+            .range((start, Bound::Unbounded))
+            .take(limit.min(MAX_MEMBER_PAGE) as usize)
+            .map(|(principal, member)| MemberEntry { principal: principal.0, member })
+            .collect()
+    })
+}
+
+/// Hands the caller's vote to another member on every member proposal about
+/// `topic` that closes while the delegation stands, unless the caller votes
+/// themselves. Without a topic it sets the default delegate, followed on
+/// every topic the caller has no delegate for.
+#[update(guard = "caller_is_authenticated")]
+fn delegate(to: candid::Principal, topic: Option<Topic>) -> Result<(), VoteError> {
+    set_delegation(caller(), to, topic)
+}
+
+fn set_delegation(delegator: candid::Principal, to: candid::Principal, topic: Option<Topic>) -> Result<(), VoteError> {
+    if !is_member(delegator) {
+        return Err(VoteError::NotAMember);
+    } else if to == delegator || !is_member(to) {
+        return Err(VoteError::InvalidDelegate);
+    }
+
+    match topic {
+        Some(topic) => {
+            check_delegation_cycle(delegator, to, std::iter::once(topic))?;
+            TOPIC_DELEGATIONS.with(|d| {
+                d.borrow_mut()
+                    .insert((topic.id(), StablePrincipal(delegator)), StablePrincipal(to))
+            });
+        }
+        None => {
+            check_delegation_cycle(delegator, to, default_topics(delegator))?;
+            DELEGATIONS.with(|d| d.borrow_mut().insert(StablePrincipal(delegator), StablePrincipal(to)));
+        }
+    }
+    Ok(())
+}
+
+/// Revokes the caller's delegation on `topic`, or their default delegation.
+#[update(guard = "caller_is_authenticated")]
+fn undelegate(topic: Option<Topic>) -> Result<(), VoteError> {
+    revoke_delegation(caller(), topic)
+}
+
+fn revoke_delegation(delegator: candid::Principal, topic: Option<Topic>) -> Result<(), VoteError> {
+    let Some(topic) = topic else {
+        return DELEGATIONS
+            .with(|d| d.borrow_mut().remove(&StablePrincipal(delegator)))
+            .map(|_| ())
+            .ok_or(VoteError::NotDelegating);
+    };
+
+    if delegate_of(delegator, Some(topic)).is_none() {
+        return Err(VoteError::NotDelegating);
+    }
+    // The topic falls back to the default delegate, which may lead back to
+    // the caller through delegations on that topic.
+    if let Some(default) = delegate_of(delegator, None) {
+        check_delegation_cycle(delegator, default, std::iter::once(topic))?;
+    }
+
+    TOPIC_DELEGATIONS.with(|d| d.borrow_mut().remove(&(topic.id(), StablePrincipal(delegator))));
+    Ok(())
+}
+
+/// Returns the delegate `principal` follows on `topic`, falling back to
+/// their default delegate, or their default delegate when `topic` is `None`.
+#[query(guard = "caller_is_observer")]
+fn get_delegate(principal: candid::Principal, topic: Option<Topic>) -> Option<candid::Principal> {
+    match topic {
+        Some(topic) => delegate_on(principal, topic),
+        None => delegate_of(principal, None),
+    }
+}
+
+/// Returns up to `limit` delegations on `topic` (or default delegations when
+/// `topic` is `None`) ordered by delegator, starting after `cursor` (the last
+/// delegator of the previous page).
+#[query(guard = "caller_is_observer")]
+fn list_delegations(topic: Option<Topic>, cursor: Option<candid::Principal>, limit: u32) -> Vec<Delegation> {
+    let limit: usize = limit.min(MAX_MEMBER_PAGE) as usize;
+
+    let Some(topic) = topic else {
+        let start = match cursor {
+            Some(principal) => Bound::Excluded(StablePrincipal(principal)),
+            None => Bound::Unbounded,
+        };
+        return DELEGATIONS.with(|d| {
+            d.borrow()
+                .range((start, Bound::Unbounded))
+                .take(limit)
+                .map(|(delegator, delegate)| Delegation { delegator: delegator.0, delegate: delegate.0 })
+                .collect()
+        });
+    };
+
+    let start = match cursor {
+        Some(principal) => Bound::Excluded((topic.id(), StablePrincipal(principal))),
+        None => Bound::Included((topic.id(), StablePrincipal(candid::Principal::management_canister()))),
+    };
+    TOPIC_DELEGATIONS.with(|d| {
+        d.borrow()
+            .range((start, Bound::Unbounded))
+            .take_while(|((id, _), _)| *id == topic.id())
+            .take(limit)
+            .map(|((_, delegator), delegate)| Delegation { delegator: delegator.0, delegate: delegate.0 })
+            .collect()
+    })
+}
+
+#[update(guard = "caller_is_authenticated")]
+async fn create_proposal(proposal: CreateProposal) -> Result<u64, VoteError> {
+    check_membership(with_config(|c| c.members_only_create), Role::Proposer)?;
+
+    // Validate the settings up front so bad ones never allocate an ID.
+    if proposal.description.len() > MAX_DESCRIPTION_LEN {
+        return Err(VoteError::DescriptionTooLong);
+    }
+    voting_deadline(&proposal.voting_period)?;
+    validate_wait_for_quiet(&proposal)?;
+    validate_secret_ballot(&proposal)?;
+    validate_blind_results(&proposal)?;
+    let rules: DecisionRules = proposal.rules.unwrap_or_default();
+    validate_rules(&rules)?;
+    let kind: ProposalKind = proposal.kind.unwrap_or(ProposalKind::YesNo);
+    validate_kind(&kind)?;
+    validate_kind_rules(&kind, &proposal.voting_period)?;
+    if let Some(size) = proposal.jury_size {
+        validate_jury_size(size)?;
+    }
+
+    let now: u64 = time();
+    let mut value: Proposal = Proposal {
+        description: proposal.description,
+        option_tallies: vec![OptionTally::default(); options(&kind).len()],
+        kind,
+        topic: proposal.topic.unwrap_or_default(),
+        allow_vote_changes: proposal.allow_vote_changes.unwrap_or(true),
+        headcount: Tally::default(),
+        weighted: Tally::default(),
+        status: ProposalStatus::Draft,
+        owner: caller(),
+        created_at: now,
+        voting_period: proposal.voting_period,
+        voting_ends_at: None,
+        rules,
+        electorate: None,
+        ledger: proposal.ledger,
+        wait_for_quiet: proposal.wait_for_quiet,
+        deadline_extended_by: 0,
+        secret_ballot: proposal.secret_ballot,
+        reveal_ends_at: None,
+        blind_results: proposal.blind_results.unwrap_or(false),
+        conviction: None,
+        jury: proposal.jury_size.map(largest_jury),
+        outcome: None,
+        history: vec![HistoryEntry {
+            at: now,
+            event: ProposalEvent::StatusChanged(ProposalStatus::Draft),
+        }],
+    };
+    check_proposal_size(&value)?;
+
+    if let Some(size) = proposal.jury_size {
+        let (seed,): (Vec<u8>,) = raw_rand()
+            .await
+            .map_err(|(code, message)| VoteError::RandomnessUnavailable(format!("{:?}: {}", code, message)))?;
+        // Members may have left while the call was in flight.
+        validate_jury_size(size)?;
+        value.jury = Some(Jury {
+            jurors: draw_jury(&seed, size as usize),
+            seed,
+        });
+    }
+
+    let key: u64 = allocate_proposal_id()?;
+    if PROPOSAL_MAP.with(|p| p.borrow().contains_key(&key)) {
+        return Err(VoteError::ProposalAlreadyExists);
+    }
+
+    // Jurors are members, so jury proposals always have a snapshot of them.
+    let mut snapshot_cursor: Option<candid::Principal> = None;
+    if value.ledger.is_none() {
+        if let Some(jury) = &value.jury {
+            value.electorate = Some(snapshot_jury(key, jury));
+        } else if with_config(|c| c.members_only_vote) {
+            snapshot_cursor = snapshot_members(key, &mut value, None);
+        }
+    }
+
+    match snapshot_cursor {
+        // The rest of a large registry is copied by later messages, and the
+        // proposal stays in Draft until then.
+        Some(cursor) => {
+            let pending: PendingSnapshot = PendingSnapshot {
+                cursor,
+                open: proposal.open,
+            };
+            PENDING_SNAPSHOTS.with(|s| s.borrow_mut().insert(key, pending));
+            schedule_snapshot(key);
+        }
+        None if proposal.open => open_proposal_now(key, &mut value)?,
+        None => {}
+    }
+
+    store_proposal(key, value);
+    Ok(key)
+}
+
+/// Replaces the description, voting period, rules and kind of a draft proposal, and
+/// opens it when `proposal.open` is set. The source of voting power and the
+/// jury are fixed at creation.
+#[update(guard = "caller_is_authenticated")]
+fn edit_proposal(key: u64, proposal: CreateProposal) -> Result<(), VoteError> {
+    let old_proposal: Proposal = owned_proposal(key)?;
+
+    if old_proposal.status != ProposalStatus::Draft {
+        return Err(VoteError::ProposalNotEditable);
+    }
+
+    if proposal.description.len() > MAX_DESCRIPTION_LEN {
+        return Err(VoteError::DescriptionTooLong);
+    }
+    voting_deadline(&proposal.voting_period)?;
+    validate_wait_for_quiet(&proposal)?;
+    validate_secret_ballot(&proposal)?;
+    validate_blind_results(&proposal)?;
+    let rules: DecisionRules = proposal.rules.unwrap_or_default();
+    validate_rules(&rules)?;
+    let kind: ProposalKind = proposal.kind.unwrap_or(ProposalKind::YesNo);
+    validate_kind(&kind)?;
+    validate_kind_rules(&kind, &proposal.voting_period)?;
+
+    let mut value: Proposal = Proposal {
+        description: proposal.description,
+        voting_period: proposal.voting_period,
+        rules,
+        option_tallies: vec![OptionTally::default(); options(&kind).len()],
+        kind,
+        topic: proposal.topic.unwrap_or_default(),
+        allow_vote_changes: proposal.allow_vote_changes.unwrap_or(true),
+        wait_for_quiet: proposal.wait_for_quiet,
+        secret_ballot: proposal.secret_ballot,
+        blind_results: proposal.blind_results.unwrap_or(false),
+        ..old_proposal
+    };
+    check_proposal_size(&value)?;
+
+    // While its electorate is still being copied, the proposal opens once
+    // the copy completes if the last edit asks for it.
+    match PENDING_SNAPSHOTS.with(|s| s.borrow().get(&key)) {
+        Some(pending) => {
+            let pending: PendingSnapshot = PendingSnapshot {
+                open: proposal.open,
+                ..pending
+            };
+            PENDING_SNAPSHOTS.with(|s| s.borrow_mut().insert(key, pending));
+        }
+        None if proposal.open => open_proposal_now(key, &mut value)?,
+        None => {}
+    }
+
+    store_proposal(key, value);
+    Ok(())
+}
+
+#[update(guard = "caller_is_authenticated")]
+fn open_proposal(key: u64) -> Result<(), VoteError> {
+    let mut proposal: Proposal = owned_proposal(key)?;
+
+    open_proposal_now(key, &mut proposal)?;
+
+    store_proposal(key, proposal);
+    Ok(())
+}
+
+#[update(guard = "caller_is_authenticated")]
+fn end_proposal(key: u64) -> Result<(), VoteError> {
+    let mut proposal: Proposal = moderated_proposal(key)?;
+
+    end_voting(key, &mut proposal)?;
+
+    store_proposal(key, proposal);
+    Ok(())
+}
+
+#[update(guard = "caller_is_authenticated")]
+fn cancel_proposal(key: u64) -> Result<(), VoteError> {
+    let mut proposal: Proposal = moderated_proposal(key)?;
+
+    transition(&mut proposal, ProposalStatus::Cancelled)?;
+    cancel_deadline(key);
+
+    store_proposal(key, proposal);
+    Ok(())
+}
+
+/// Marks a passed proposal as executed.
+#[update(guard = "caller_is_authenticated")]
+fn execute_proposal(key: u64) -> Result<(), VoteError> {
+    let mut proposal: Proposal = owned_proposal(key)?;
+
+    transition(&mut proposal, ProposalStatus::Executed)?;
+
+    store_proposal(key, proposal);
+    Ok(())
+}
+
+/// Moves `amount` tokens from the caller's account on the ledger of an open
+/// token-weighted proposal into the proposal's escrow, using an ICRC-2
+/// allowance the caller gave this canister beforehand. The caller's escrow
+/// is their voting power on the proposal; the ledger fee is paid on top.
+#[update(guard = "caller_is_authenticated")]
+async fn escrow_tokens(key: u64, amount: u64) -> Result<(), VoteError> {
+    let voter = caller();
+    let proposal: Proposal = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .ok_or(VoteError::NoSuchProposal)?;
+    let ledger: candid::Principal = proposal.ledger.ok_or(VoteError::NotTokenWeighted)?;
+    if amount == 0 {
+        return Err(VoteError::NoVotingPower);
+    } else if proposal.status != ProposalStatus::Open {
+        return Err(VoteError::ProposalIsNotActive);
+    } else if proposal.voting_ends_at.is_some_and(|ends_at| ends_at <= time()) {
+        return Err(VoteError::VotingPeriodEnded);
+    }
+
+    let args: TransferFromArgs = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: voter,
+            subaccount: None,
+        },
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(escrow_subaccount(key)),
+        },
+        amount: candid::Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    transfer_result(ic_cdk::call(ledger, "icrc2_transfer_from", (args,)).await)?;
+
+    // The tokens are in escrow whatever happened to the proposal meanwhile,
+    // so they are always recorded to be withdrawn later.
+    ELECTORATE.with(|e| {
+        let mut electorate = e.borrow_mut();
+        let escrowed: u64 = electorate.get(&(key, StablePrincipal(voter))).unwrap_or(0);
+        electorate.insert((key, StablePrincipal(voter)), escrowed.saturating_add(amount));
+    });
+    Ok(())
+}
+
+/// Returns the caller's escrowed tokens, minus the ledger fee, once voting on
+/// the proposal has ended. Returns the amount transferred back.
+#[update(guard = "caller_is_authenticated")]
+async fn withdraw_tokens(key: u64) -> Result<u64, VoteError> {
+    let voter = caller();
+    let proposal: Proposal = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .ok_or(VoteError::NoSuchProposal)?;
+    let ledger: candid::Principal = proposal.ledger.ok_or(VoteError::NotTokenWeighted)?;
+    if matches!(
+        proposal.status,
+        ProposalStatus::Draft | ProposalStatus::Open | ProposalStatus::Revealing
+    ) {
+        return Err(VoteError::TokensLocked);
+    }
+
+    let (fee,): (candid::Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, message)| VoteError::LedgerCallFailed(format!("{:?}: {}", code, message)))?;
+    let fee: u64 = u64::try_from(fee.0).unwrap_or(u64::MAX);
+
+    // The escrow is removed before the transfer so a concurrent call cannot
+    // withdraw it twice, and put back if the transfer fails.
+    let escrowed: u64 = ELECTORATE
+        .with(|e| e.borrow_mut().remove(&(key, StablePrincipal(voter))))
+        .ok_or(VoteError::NoVotingPower)?;
+    let amount: u64 = escrowed.saturating_sub(fee);
+    if amount == 0 {
+        return Ok(0);
+    }
+
+    let args: TransferArg = TransferArg {
+        from_subaccount: Some(escrow_subaccount(key)),
+        to: Account {
+            owner: voter,
+            subaccount: None,
+        },
+        amount: candid::Nat::from(amount),
+        fee: Some(candid::Nat::from(fee)),
+        memo: None,
+        created_at_time: None,
+    };
+    if let Err(error) = transfer_result(ic_cdk::call(ledger, "icrc1_transfer", (args,)).await) {
+        ELECTORATE.with(|e| e.borrow_mut().insert((key, StablePrincipal(voter)), escrowed));
+        return Err(error);
+    }
+    Ok(amount)
+}
+
+#[update(guard = "caller_is_authenticated")]
+fn vote(key: u64, choice: Choice, reason: Option<String>) -> Result<(), VoteError> {
+    if reason.as_ref().is_some_and(|r| r.len() > MAX_REASON_LEN) {
+        return Err(VoteError::ReasonTooLong);
+    }
+
+    let proposal: Proposal = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .ok_or(VoteError::NoSuchProposal)?;
+    if proposal.secret_ballot.is_some() {
+        return Err(VoteError::CommitmentRequired);
+    }
+
+    cast_vote(key, caller(), choice, reason)
+}
+
+fn check_can_vote(key: u64, proposal: &Proposal, voter: candid::Principal) -> Result<(), VoteError> {
+    // Member proposals with a snapshot only accept voters from it, token
+    // proposals anyone with tokens in escrow; the registry and roles are checked for
+    // the others.
+    if proposal.electorate.is_none() && proposal.ledger.is_none() {
+        check_membership(with_config(|c| c.members_only_vote), Role::Voter)?;
+    }
+
+    if proposal.jury.as_ref().is_some_and(|jury| !jury.jurors.contains(&voter)) {
+        return Err(VoteError::NotAJuror);
+    }
+
+    if BALLOTS.with(|b| b.borrow().contains_key(&(key, StablePrincipal(voter)))) {
+        return Err(VoteError::AlreadyVoted);
+    } else if proposal.status != ProposalStatus::Open {
+        return Err(VoteError::ProposalIsNotActive);
+    } else if proposal.voting_ends_at.is_some_and(|ends_at| ends_at <= time()) {
+        return Err(VoteError::VotingPeriodEnded);
+    };
+    Ok(())
+}
+
+fn cast_vote(key: u64, voter: candid::Principal, choice: Choice, reason: Option<String>) -> Result<(), VoteError> {
+    PROPOSAL_MAP.with(|p| {
+        let proposal_opt: Option<Proposal> = p.borrow().get(&key);
+        let mut proposal = proposal_opt.ok_or(VoteError::NoSuchProposal)?;
+
+        check_can_vote(key, &proposal, voter)?;
+        validate_choice(&proposal.kind, &choice)?;
+
+        let weight: u64 = voting_weight(key, &proposal, voter)?;
+        let outcome_before: Outcome = tally_outcome(&proposal);
+
+        spend_credits(key, &proposal, voter, &choice, 0)?;
+        let weight: u64 = stake_support(key, &proposal, voter, &choice, weight)?;
+        count_ballot(&mut proposal, &choice, weight);
+
+        let ballot: Ballot = Ballot {
+            choice,
+            weight,
+            cast_at: time(),
+            reason,
+            via: None,
+        };
+
+        BALLOTS.with(|b| b.borrow_mut().insert((key, StablePrincipal(voter)), ballot));
+
+        settle_ballot(key, &mut proposal, outcome_before)?;
+
+        p.borrow_mut()
+            .insert(key, proposal)
+            .map(|_| ())
+            .ok_or(VoteError::UpdateError("Insert failed".to_string()))
+    })
+}
+
+/// Checks that `voter` may commit to a ballot on a secret-ballot proposal.
+fn check_can_commit(key: u64, proposal: &Proposal, voter: candid::Principal) -> Result<(), VoteError> {
+    if proposal.secret_ballot.is_none() {
+        return Err(VoteError::NotASecretBallot);
+    }
+    check_can_vote(key, proposal, voter)?;
+
+    if COMMITMENTS.with(|c| c.borrow().contains_key(&(key, StablePrincipal(voter)))) {
+        return Err(VoteError::AlreadyVoted);
+    }
+    Ok(())
+}
+
+/// Commits the caller to a hidden choice on an open secret-ballot proposal.
+/// `commitment` is the SHA-256 hash of the canonical encoding of the choice
+/// (see `choice_bytes`) followed by a salt the voter keeps until the reveal.
+#[update(guard = "caller_is_authenticated")]
+fn commit_vote(key: u64, commitment: Vec<u8>) -> Result<(), VoteError> {
+    if commitment.len() != COMMITMENT_LEN {
+        return Err(VoteError::InvalidCommitment);
+    }
+
+    let voter = caller();
+    let proposal: Proposal = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .ok_or(VoteError::NoSuchProposal)?;
+    check_can_commit(key, &proposal, voter)?;
+    voting_weight(key, &proposal, voter)?;
+
+    let value: Commitment = Commitment {
+        hash: commitment,
+        committed_at: time(),
+    };
+    COMMITMENTS.with(|c| c.borrow_mut().insert((key, StablePrincipal(voter)), value));
+    Ok(())
+}
+
+/// Reveals the choice the caller committed to during the reveal window and
+/// counts it. Reveals that do not match the commitment are rejected.
+#[update(guard = "caller_is_authenticated")]
+fn reveal_vote(key: u64, choice: Choice, salt: Vec<u8>, reason: Option<String>) -> Result<(), VoteError> {
+    if reason.as_ref().is_some_and(|r| r.len() > MAX_REASON_LEN) {
+        return Err(VoteError::ReasonTooLong);
+    }
+
+    let voter = caller();
+    let mut proposal: Proposal = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .ok_or(VoteError::NoSuchProposal)?;
+
+    if proposal.status != ProposalStatus::Revealing {
+        return Err(VoteError::ProposalIsNotActive);
+    } else if proposal.reveal_ends_at.is_some_and(|ends_at| ends_at <= time()) {
+        return Err(VoteError::VotingPeriodEnded);
+    } else if BALLOTS.with(|b| b.borrow().contains_key(&(key, StablePrincipal(voter)))) {
+        return Err(VoteError::AlreadyVoted);
+    }
+
+    let commitment: Commitment = COMMITMENTS
+        .with(|c| c.borrow().get(&(key, StablePrincipal(voter))))
+        .ok_or(VoteError::NotCommitted)?;
+    if commitment_hash(&choice, &salt) != commitment.hash {
+        return Err(VoteError::CommitmentMismatch);
+    }
+    validate_choice(&proposal.kind, &choice)?;
+
+    let weight: u64 = voting_weight(key, &proposal, voter)?;
+    spend_credits(key, &proposal, voter, &choice, 0)?;
+    count_ballot(&mut proposal, &choice, weight);
+
+    let ballot: Ballot = Ballot {
+        choice,
+        weight,
+        cast_at: time(),
+        reason,
+        via: None,
+    };
+    BALLOTS.with(|b| b.borrow_mut().insert((key, StablePrincipal(voter)), ballot));
+
+    store_proposal(key, proposal);
+    Ok(())
+}
+
+/// Closes the proposal once a ballot settled its outcome, or else applies
+/// wait-for-quiet. Conviction proposals are rescheduled to pass when their
+/// conviction will reach the threshold under the new support.
+fn settle_ballot(key: u64, proposal: &mut Proposal, outcome_before: Outcome) -> Result<(), VoteError> {
+    if settled_outcome(proposal).is_some() || conviction_passes_at(proposal).is_some_and(|at| at <= time()) {
+        close_proposal(key, proposal)?;
+        cancel_deadline(key);
+    } else if let ProposalKind::Conviction(_) = proposal.kind {
+        match conviction_passes_at(proposal) {
+            Some(passes_at) => schedule_deadline(key, passes_at),
+            None => cancel_deadline(key),
+        }
+    } else {
+        wait_for_quiet(key, proposal, outcome_before);
+    }
+    Ok(())
+}
+
+/// Loads an open proposal accepting ballot changes and the caller's ballot
+/// on it.
+fn changeable_ballot(key: u64, voter: candid::Principal) -> Result<(Proposal, Ballot), VoteError> {
+    let proposal: Proposal = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .ok_or(VoteError::NoSuchProposal)?;
+
+    if proposal.status != ProposalStatus::Open {
+        return Err(VoteError::ProposalIsNotActive);
+    } else if proposal.voting_ends_at.is_some_and(|ends_at| ends_at <= time()) {
+        return Err(VoteError::VotingPeriodEnded);
+    } else if !proposal.allow_vote_changes {
+        return Err(VoteError::VoteChangesDisabled);
+    }
+
+    let ballot: Ballot = BALLOTS
+        .with(|b| b.borrow().get(&(key, StablePrincipal(voter))))
+        .ok_or(VoteError::NotVoted)?;
+    Ok((proposal, ballot))
+}
+
+/// Loads the earlier ballots of `voter`, checking there is room for one more.
+fn ballot_history(key: u64, voter: candid::Principal) -> Result<BallotHistory, VoteError> {
+    let history: BallotHistory = BALLOT_HISTORY
+        .with(|h| h.borrow().get(&(key, StablePrincipal(voter))))
+        .unwrap_or_default();
+
+    if history.ballots.len() >= MAX_BALLOT_CHANGES {
+        return Err(VoteError::TooManyBallotChanges);
+    }
+    Ok(history)
+}
+
+/// Replaces the caller's ballot on an open proposal. The new choice is
+/// counted with the weight of the ballot it replaces, or with its amount for
+/// a new stake, and the old ballot is kept in the ballot history.
+#[update(guard = "caller_is_authenticated")]
+fn change_vote(key: u64, choice: Choice, reason: Option<String>) -> Result<(), VoteError> {
+    if reason.as_ref().is_some_and(|r| r.len() > MAX_REASON_LEN) {
+        return Err(VoteError::ReasonTooLong);
+    }
+
+    let voter = caller();
+    let (mut proposal, old_ballot) = changeable_ballot(key, voter)?;
+    validate_choice(&proposal.kind, &choice)?;
+    let mut history: BallotHistory = ballot_history(key, voter)?;
+
+    let outcome_before: Outcome = tally_outcome(&proposal);
+    let refund: u64 = ballot_cost(&proposal, &old_ballot.choice);
+    spend_credits(key, &proposal, voter, &choice, refund)?;
+    // A new stake is checked against the voter's current weight.
+    let weight: u64 = match choice {
+        Choice::Stake(_) => voting_weight(key, &proposal, voter)?,
+        _ => old_ballot.weight,
+    };
+    let weight: u64 = stake_support(key, &proposal, voter, &choice, weight)?;
+
+    uncount_ballot(&mut proposal, &old_ballot.choice, old_ballot.weight);
+    count_ballot(&mut proposal, &choice, weight);
+
+    let now: u64 = time();
+    let ballot: Ballot = Ballot {
+        choice,
+        weight,
+        cast_at: now,
+        reason,
+        via: None,
+    };
+    history.ballots.push(PastBallot {
+        ballot: old_ballot,
+        superseded_at: now,
+        retracted: false,
+    });
+    BALLOTS.with(|b| b.borrow_mut().insert((key, StablePrincipal(voter)), ballot));
+    BALLOT_HISTORY.with(|h| h.borrow_mut().insert((key, StablePrincipal(voter)), history));
+
+    settle_ballot(key, &mut proposal, outcome_before)?;
+    store_proposal(key, proposal);
+    Ok(())
+}
+
+/// Withdraws the caller's ballot from an open proposal and refunds any
+/// credits or releases any stake it held. The caller may vote again afterwards.
+#[update(guard = "caller_is_authenticated")]
+fn retract_vote(key: u64) -> Result<(), VoteError> {
+    let voter = caller();
+    let (mut proposal, old_ballot) = changeable_ballot(key, voter)?;
+    let mut history: BallotHistory = ballot_history(key, voter)?;
+
+    let outcome_before: Outcome = tally_outcome(&proposal);
+    let refund: u64 = ballot_cost(&proposal, &old_ballot.choice);
+    spend_credits(key, &proposal, voter, &Choice::Pass, refund)?;
+    stake_support(key, &proposal, voter, &Choice::Pass, 0)?;
+
+    uncount_ballot(&mut proposal, &old_ballot.choice, old_ballot.weight);
+
+    history.ballots.push(PastBallot {
+        ballot: old_ballot,
+        superseded_at: time(),
+        retracted: true,
+    });
+    BALLOTS.with(|b| b.borrow_mut().remove(&(key, StablePrincipal(voter))));
+    BALLOT_HISTORY.with(|h| h.borrow_mut().insert((key, StablePrincipal(voter)), history));
+
+    settle_ballot(key, &mut proposal, outcome_before)?;
+    store_proposal(key, proposal);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts `(weight, choice)` ballots round by round, as the batches of
+    /// `tally_next_batch` do, and returns the winner with every round.
+    fn count(kind: &ProposalKind, ballots: &[(u64, Choice)]) -> (Option<u32>, Vec<TallyRound>) {
+        let mut rounds: Vec<TallyRound> = vec![empty_round(options(kind).len())];
+        loop {
+            for (weight, choice) in ballots {
+                count_in_round(kind, &mut rounds, choice, *weight);
+            }
+            if let Some(winner) = end_round(kind, &mut rounds) {
+                return (winner, rounds);
+            }
+        }
+    }
+
+    fn labels(count: usize) -> Vec<String> {
+        (0..count).map(|index| format!("Option {}", index)).collect()
+    }
+
+    #[test]
+    fn instant_runoff_breaks_ties_by_first_preferences_then_listed_last() {
+        let kind: ProposalKind = ProposalKind::RankedChoice(labels(3));
+        let ballots: Vec<(u64, Choice)> = vec![
+            (1, Choice::Ranking(vec![0])),
+            (1, Choice::Ranking(vec![0])),
+            (1, Choice::Ranking(vec![1])),
+            (1, Choice::Ranking(vec![2, 1])),
+            (5, Choice::Pass),
+        ];
+
+        let (winner, rounds) = count(&kind, &ballots);
+
+        // Options 1 and 2 tie on first preferences, so the one listed last
+        // goes first.
+        assert_eq!(rounds[0].counts, vec![2, 1, 1]);
+        assert_eq!(rounds[0].eliminated, vec![2]);
+        // Options 0 and 1 then tie, and option 1 had fewer first preferences.
+        assert_eq!(rounds[1].counts, vec![2, 2, 0]);
+        assert_eq!(rounds[1].eliminated, vec![1]);
+        assert_eq!(rounds[2].counts, vec![2, 0, 0]);
+        assert_eq!(rounds[2].exhausted, 2);
+        assert_eq!(winner, Some(0));
+    }
+
+    #[test]
+    fn approval_ties_go_to_the_option_listed_first() {
+        let kind: ProposalKind = ProposalKind::Approval(labels(3));
+        let ballots: Vec<(u64, Choice)> = vec![
+            (1, Choice::Approvals(vec![0, 1])),
+            (1, Choice::Approvals(vec![1, 2])),
+            (1, Choice::Approvals(vec![0])),
+        ];
+
+        let (winner, rounds) = count(&kind, &ballots);
+
+        assert_eq!(rounds.len(), 1);
+        assert_eq!(rounds[0].counts, vec![2, 2, 1]);
+        assert_eq!(winner, Some(0));
+    }
+
+    #[test]
+    fn star_runoff_tie_goes_to_the_scoring_leader() {
+        let kind: ProposalKind = ProposalKind::Star(labels(3));
+        let ballots: Vec<(u64, Choice)> = vec![
+            (1, Choice::Scores(vec![4, 0, 0])),
+            (1, Choice::Scores(vec![0, 5, 0])),
+            (1, Choice::Scores(vec![3, 3, 1])),
+        ];
+
+        let (winner, rounds) = count(&kind, &ballots);
+
+        assert_eq!(rounds[0].counts, vec![7, 8, 1]);
+        assert_eq!(rounds[0].eliminated, vec![2]);
+        // Each finalist is preferred by one ballot and the third scores them
+        // equally, so option 1, which led the scoring round, wins although
+        // option 0 is listed first.
+        assert_eq!(rounds[1].counts, vec![1, 1, 0]);
+        assert_eq!(rounds[1].exhausted, 1);
+        assert_eq!(rounds[1].eliminated, vec![0]);
+        assert_eq!(winner, Some(1));
+    }
+
+    #[test]
+    fn choice_bytes_follow_the_documented_layout() {
+        assert_eq!(choice_bytes(&Choice::Pass), vec![2]);
+        assert_eq!(choice_bytes(&Choice::Option(2)), vec![3, 2, 0, 0, 0]);
+        assert_eq!(
+            choice_bytes(&Choice::Ranking(vec![1, 256])),
+            vec![4, 2, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 0]
+        );
+        assert_eq!(choice_bytes(&Choice::Scores(vec![5, 0])), vec![6, 2, 0, 0, 0, 5, 0]);
+        assert_eq!(choice_bytes(&Choice::Stake(258)), vec![8, 2, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn legacy_proposals_are_told_apart_and_converted() {
+        let legacy: LegacyProposal = LegacyProposal {
+            description: "é".repeat(800),
+            approve: 3,
+            reject: 1,
+            pass: 2,
+            is_active: false,
+            voted: vec![candid::Principal::anonymous()],
+            owner: candid::Principal::anonymous(),
+        };
+        let bytes: Vec<u8> = Encode!(&legacy).unwrap();
+        assert!(Decode!(&bytes, Proposal).is_err());
+
+        let proposal: Proposal = convert_legacy_proposal(Decode!(&bytes, LegacyProposal).unwrap(), 7);
+        assert!(proposal.status == ProposalStatus::Closed);
+        assert!(proposal.outcome == Some(Outcome::Passed));
+        assert_eq!(proposal.weighted.approve, 3);
+        assert_eq!(proposal.headcount.pass, 2);
+        assert_eq!(proposal.history.len(), 3);
+        assert_eq!(proposal.description.len(), MAX_DESCRIPTION_LEN);
+
+        let bytes: Vec<u8> = Encode!(&proposal).unwrap();
+        assert!(bytes.len() <= MAX_VALUE_SIZE as usize);
+        assert!(Decode!(&bytes, LegacyProposal).is_err());
+    }
+
+    const SECOND: u64 = 1_000_000_000;
+
+    /// Open proposal of `kind` with no ballots and the default rules.
+    fn proposal(kind: ProposalKind) -> Proposal {
+        Proposal {
+            description: String::new(),
+            option_tallies: vec![OptionTally::default(); options(&kind).len()],
+            kind,
+            topic: Topic::default(),
+            allow_vote_changes: true,
+            headcount: Tally::default(),
+            weighted: Tally::default(),
+            status: ProposalStatus::Open,
+            owner: candid::Principal::anonymous(),
+            created_at: 0,
+            voting_period: None,
+            voting_ends_at: None,
+            rules: DecisionRules::default(),
+            electorate: None,
+            ledger: None,
+            wait_for_quiet: None,
+            deadline_extended_by: 0,
+            secret_ballot: None,
+            reveal_ends_at: None,
+            outcome: None,
+            history: vec![],
+            blind_results: false,
+            conviction: None,
+            jury: None,
+        }
+    }
+
+    /// Conviction proposal whose conviction was `value` at `updated_at` with
+    /// `staked` support.
+    fn conviction_proposal(params: ConvictionParams, value: f64, updated_at: u64, staked: u64) -> Proposal {
+        Proposal {
+            weighted: Tally {
+                approve: staked,
+                ..Tally::default()
+            },
+            conviction: Some(Conviction { value, updated_at }),
+            ..proposal(ProposalKind::Conviction(params))
+        }
+    }
+
+    /// Principal number `n`, distinct for every `n`.
+    fn principal(n: u32) -> candid::Principal {
+        let mut bytes: [u8; 29] = [0; 29];
+        bytes[..4].copy_from_slice(&n.to_be_bytes());
+        candid::Principal::from_slice(&bytes)
+    }
+
+    #[test]
+    fn snapshot_copies_voters_in_batches() {
+        let count: u32 = SNAPSHOT_BATCH_SIZE as u32 + 10;
+        for n in 0..count {
+            let member: Member = Member { joined_at: 0, weight: 2 };
+            MEMBERS.with(|m| m.borrow_mut().insert(StablePrincipal(principal(n)), member));
+            // Every third member has had the Voter role revoked.
+            if n % 3 != 0 {
+                set_role(principal(n), Role::Voter, true);
+            }
+        }
+
+        let key: u64 = 1;
+        let mut value: Proposal = proposal(ProposalKind::YesNo);
+        let cursor: Option<candid::Principal> = snapshot_members(key, &mut value, None);
+        assert!(cursor == Some(principal(SNAPSHOT_BATCH_SIZE as u32 - 1)));
+        assert_eq!(snapshot_members(key, &mut value, cursor), None);
+
+        let voters: u64 = (0..count).filter(|n| n % 3 != 0).count() as u64;
+        let electorate: Electorate = value.electorate.expect("electorate is snapshotted");
+        assert_eq!(electorate.size, voters);
+        assert_eq!(electorate.total_weight, 2 * voters);
+        assert!(ELECTORATE.with(|e| !e.borrow().contains_key(&(key, StablePrincipal(principal(0))))));
+        assert_eq!(ELECTORATE.with(|e| e.borrow().get(&(key, StablePrincipal(principal(1))))), Some(2));
+    }
+
+    #[test]
+    fn stakes_are_capped_across_open_conviction_proposals() {
+        let params: ConvictionParams = ConvictionParams {
+            requested: 1000,
+            half_life: 3600 * SECOND,
+            threshold_bps: 5000,
+        };
+        let voter: candid::Principal = principal(7);
+        store_proposal(10, proposal(ProposalKind::Conviction(params.clone())));
+        store_proposal(11, proposal(ProposalKind::Conviction(params)));
+        let first: Proposal = PROPOSAL_MAP.with(|p| p.borrow().get(&10)).unwrap();
+        let second: Proposal = PROPOSAL_MAP.with(|p| p.borrow().get(&11)).unwrap();
+
+        assert!(matches!(stake_support(10, &first, voter, &Choice::Stake(60), 100), Ok(60)));
+        assert!(matches!(
+            stake_support(11, &second, voter, &Choice::Stake(50), 100),
+            Err(VoteError::InsufficientStake { requested: 50, available: 40 })
+        ));
+        // Restaking on the same proposal replaces the earlier stake.
+        assert!(matches!(stake_support(10, &first, voter, &Choice::Stake(30), 100), Ok(30)));
+        assert!(matches!(stake_support(11, &second, voter, &Choice::Stake(50), 100), Ok(50)));
+
+        // Once the first proposal closes its stake is freed.
+        let mut closed: Proposal = PROPOSAL_MAP.with(|p| p.borrow().get(&10)).unwrap();
+        closed.status = ProposalStatus::Closed;
+        store_proposal(10, closed);
+        assert_eq!(staked_elsewhere(voter, 11), 0);
+        assert!(STAKES.with(|s| !s.borrow().contains_key(&(StablePrincipal(voter), 10))));
+
+        // Passing releases the stake.
+        assert!(matches!(stake_support(11, &second, voter, &Choice::Pass, 100), Ok(100)));
+        assert!(STAKES.with(|s| !s.borrow().contains_key(&(StablePrincipal(voter), 11))));
+    }
+
+    fn rules(quorum: u64, threshold: Threshold, pass_counts_toward_quorum: bool) -> DecisionRules {
+        DecisionRules {
+            quorum,
+            threshold,
+            pass_counts_toward_quorum,
+        }
+    }
+
+    fn tally(approve: u64, reject: u64, pass: u64) -> Tally {
+        Tally { approve, reject, pass }
+    }
+
+    #[test]
+    fn supermajority_is_reached_exactly_at_the_fraction() {
+        let two_thirds: DecisionRules = rules(
+            0,
+            Threshold::Supermajority {
+                numerator: 2,
+                denominator: 3,
+            },
+            true,
+        );
+
+        assert!(decide(&two_thirds, &tally(2, 1, 0)) == Outcome::Passed);
+        assert!(decide(&two_thirds, &tally(199, 100, 0)) == Outcome::Rejected);
+        assert!(decide(&two_thirds, &tally(667, 333, 0)) == Outcome::Passed);
+        assert!(decide(&two_thirds, &tally(666, 334, 0)) == Outcome::Rejected);
+        // No approvals never pass, even with nothing against.
+        assert!(decide(&two_thirds, &tally(0, 0, 5)) == Outcome::Rejected);
+        // Huge weights do not overflow.
+        assert!(decide(&two_thirds, &tally(u64::MAX, u64::MAX / 2, 0)) == Outcome::Passed);
+    }
+
+    #[test]
+    fn quorum_counts_passes_only_when_configured() {
+        let with_pass: DecisionRules = rules(10, Threshold::SimpleMajority, true);
+        let without_pass: DecisionRules = rules(10, Threshold::SimpleMajority, false);
+
+        assert!(decide(&with_pass, &tally(4, 2, 4)) == Outcome::Passed);
+        assert!(decide(&without_pass, &tally(4, 2, 4)) == Outcome::QuorumNotReached);
+        assert!(decide(&without_pass, &tally(6, 4, 0)) == Outcome::Passed);
+        assert!(decide(&with_pass, &tally(3, 3, 3)) == Outcome::QuorumNotReached);
+    }
+
+    /// Yes/no proposal with changes disabled, an electorate of
+    /// `total_weight`, and `weighted` cast so far.
+    fn settling_proposal(rules: DecisionRules, total_weight: u64, weighted: Tally) -> Proposal {
+        Proposal {
+            allow_vote_changes: false,
+            rules,
+            electorate: Some(Electorate { size: 10, total_weight }),
+            weighted,
+            ..proposal(ProposalKind::YesNo)
+        }
+    }
+
+    #[test]
+    fn yes_no_settles_once_both_extremes_agree() {
+        let majority: DecisionRules = rules(0, Threshold::SimpleMajority, true);
+
+        // 51 of 100 approve: the other 49 cannot overturn it.
+        let passed: Proposal = settling_proposal(majority.clone(), 100, tally(51, 0, 0));
+        assert!(settled_outcome(&passed) == Some(Outcome::Passed));
+        // 50 of 100: all remaining rejections would tie, which rejects.
+        let open: Proposal = settling_proposal(majority.clone(), 100, tally(50, 0, 0));
+        assert!(settled_outcome(&open).is_none());
+        // Rejections of half the electorate already make passing impossible.
+        let rejected: Proposal = settling_proposal(majority.clone(), 100, tally(0, 50, 0));
+        assert!(settled_outcome(&rejected) == Some(Outcome::Rejected));
+        // Passes take weight out of play.
+        let passes: Proposal = settling_proposal(majority.clone(), 100, tally(30, 29, 41));
+        assert!(settled_outcome(&passes) == Some(Outcome::Passed));
+
+        // A quorum still out of reach without the remaining voters is not
+        // settled, since the current tally is the worst case for it.
+        let quorum: Proposal = settling_proposal(rules(80, Threshold::SimpleMajority, true), 100, tally(51, 0, 0));
+        assert!(settled_outcome(&quorum).is_none());
+
+        // Changeable ballots are never settled early.
+        let changeable: Proposal = Proposal {
+            allow_vote_changes: true,
+            ..settling_proposal(majority, 100, tally(100, 0, 0))
+        };
+        assert!(settled_outcome(&changeable).is_none());
+    }
+
+    #[test]
+    fn wait_for_quiet_extensions_are_capped() {
+        let mut value: Proposal = Proposal {
+            voting_ends_at: Some(100 * SECOND),
+            wait_for_quiet: Some(WaitForQuiet {
+                window: 10 * SECOND,
+                extension: 20 * SECOND,
+                max_extension: 50 * SECOND,
+            }),
+            ..proposal(ProposalKind::YesNo)
+        };
+
+        // A ballot outside the window does not extend the deadline, even if
+        // it flips the outcome.
+        value.weighted = tally(1, 0, 0);
+        assert_eq!(extend_deadline(&mut value, Outcome::Rejected, 80 * SECOND), None);
+        // Nor does one inside it that leaves the outcome as it was.
+        assert_eq!(extend_deadline(&mut value, Outcome::Passed, 95 * SECOND), None);
+
+        // Flips inside the window extend it by 20s, 20s, then the 10s left.
+        let flips: [(Outcome, u64, u64); 3] = [
+            (Outcome::Rejected, 95, 120),
+            (Outcome::Rejected, 115, 140),
+            (Outcome::Rejected, 135, 150),
+        ];
+        for (before, now, ends_at) in flips {
+            assert_eq!(extend_deadline(&mut value, before, now * SECOND), Some(ends_at * SECOND));
+        }
+        assert_eq!(value.deadline_extended_by, 50 * SECOND);
+        assert_eq!(value.history.len(), 3);
+
+        // Once the cap is used up the deadline stays.
+        assert_eq!(extend_deadline(&mut value, Outcome::Rejected, 145 * SECOND), None);
+        assert_eq!(value.voting_ends_at, Some(150 * SECOND));
+    }
+
+    /// Registers principals `0..count` as members.
+    fn add_members(count: u32) {
+        for n in 0..count {
+            let member: Member = Member { joined_at: 0, weight: 1 };
+            MEMBERS.with(|m| m.borrow_mut().insert(StablePrincipal(principal(n)), member));
+        }
+    }
+
+    #[test]
+    fn default_delegations_reject_cycles() {
+        add_members(3);
+        let (a, b, c) = (principal(0), principal(1), principal(2));
+
+        assert!(set_delegation(a, b, None).is_ok());
+        assert!(set_delegation(b, c, None).is_ok());
+        assert!(matches!(set_delegation(c, a, None), Err(VoteError::DelegationCycle)));
+
+        // Breaking the chain makes room for the delegation.
+        assert!(revoke_delegation(b, None).is_ok());
+        assert!(set_delegation(c, a, None).is_ok());
+        assert!(delegation_reaches(c, b, Topic::General));
+        assert!(!delegation_reaches(b, c, Topic::General));
+    }
+
+    #[test]
+    fn default_delegation_is_checked_only_on_topics_without_their_own_delegate() {
+        add_members(3);
+        let (a, b, c) = (principal(0), principal(1), principal(2));
+
+        // `a` only follows `b` on Finance, so `b` following `a` by default
+        // would loop there.
+        assert!(set_delegation(a, b, Some(Topic::Finance)).is_ok());
+        assert!(matches!(set_delegation(b, a, None), Err(VoteError::DelegationCycle)));
+
+        // Once `b` has their own Finance delegate the default no longer
+        // applies on Finance, and nowhere else leads back to `b`.
+        assert!(set_delegation(b, c, Some(Topic::Finance)).is_ok());
+        assert!(set_delegation(b, a, None).is_ok());
+        assert_eq!(delegate_on(b, Topic::Finance), Some(c));
+        assert_eq!(delegate_on(b, Topic::General), Some(a));
+    }
+
+    #[test]
+    fn topic_delegation_overrides_the_default() {
+        add_members(3);
+        let (a, b, c) = (principal(0), principal(1), principal(2));
+
+        assert!(set_delegation(a, b, None).is_ok());
+        assert!(set_delegation(a, c, Some(Topic::Finance)).is_ok());
+        assert_eq!(delegate_on(a, Topic::Finance), Some(c));
+        assert_eq!(delegate_on(a, Topic::General), Some(b));
+
+        // On Finance `a` follows `c`, so `c` following `a` there loops,
+        // while on General it does not.
+        assert!(matches!(set_delegation(c, a, Some(Topic::Finance)), Err(VoteError::DelegationCycle)));
+        assert!(set_delegation(c, a, Some(Topic::General)).is_ok());
+    }
+
+    #[test]
+    fn undelegate_rejects_falling_back_into_a_cycle() {
+        add_members(3);
+        let (a, b, c) = (principal(0), principal(1), principal(2));
+
+        assert!(set_delegation(a, b, None).is_ok());
+        assert!(set_delegation(a, c, Some(Topic::Finance)).is_ok());
+        // `b` following `a` on Finance is fine while `a` follows `c` there.
+        assert!(set_delegation(b, a, Some(Topic::Finance)).is_ok());
+
+        // Revoking it would send `a` back to `b` on Finance.
+        assert!(matches!(
+            revoke_delegation(a, Some(Topic::Finance)),
+            Err(VoteError::DelegationCycle)
+        ));
+        assert_eq!(delegate_on(a, Topic::Finance), Some(c));
+
+        assert!(revoke_delegation(b, Some(Topic::Finance)).is_ok());
+        assert!(revoke_delegation(a, Some(Topic::Finance)).is_ok());
+        assert_eq!(delegate_on(a, Topic::Finance), Some(b));
+        assert!(matches!(
+            revoke_delegation(a, Some(Topic::Finance)),
+            Err(VoteError::NotDelegating)
+        ));
+    }
+
+    #[test]
+    fn conviction_reaches_the_threshold_when_it_passes() {
+        let params: ConvictionParams = ConvictionParams {
+            requested: 1000,
+            half_life: 3600 * SECOND,
+            threshold_bps: 5000,
+        };
+        let threshold: f64 = conviction_threshold(&params);
+        let updated_at: u64 = 10 * SECOND;
+
+        for (value, staked) in [(0.0, 501), (100.0, 800), (499.0, 1000), (250.5, 600)] {
+            let proposal: Proposal = conviction_proposal(params.clone(), value, updated_at, staked);
+            let passes_at: u64 = conviction_passes_at(&proposal).expect("staked support exceeds the threshold");
+            let elapsed: u64 = passes_at - updated_at;
+
+            assert!(conviction_after(value, staked as f64, params.half_life, elapsed) >= threshold);
+            assert!(conviction_after(value, staked as f64, params.half_life, elapsed - 1) < threshold);
+        }
+    }
+
+    #[test]
+    fn conviction_passes_now_or_never() {
+        let params: ConvictionParams = ConvictionParams {
+            requested: 1000,
+            half_life: 3600 * SECOND,
+            threshold_bps: 5000,
+        };
+
+        let reached: Proposal = conviction_proposal(params.clone(), 500.0, 42, 0);
+        assert_eq!(conviction_passes_at(&reached), Some(42));
+
+        let short: Proposal = conviction_proposal(params, 100.0, 42, 500);
+        assert_eq!(conviction_passes_at(&short), None);
+    }
 }
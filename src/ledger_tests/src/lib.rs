@@ -0,0 +1,2 @@
+//! Integration tests of the Proposal backend against a local ICRC-1 ledger
+//! under PocketIC. The tests live in `tests/`.
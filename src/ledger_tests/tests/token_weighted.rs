@@ -0,0 +1,355 @@
+//! Token-weighted voting against an ICRC-2 ledger running next to the
+//! canister under PocketIC.
+//!
+//! Needs `POCKET_IC_BIN` pointing at a PocketIC server, `ICRC1_LEDGER_WASM`
+//! pointing at the ICRC-1 ledger wasm (which implements ICRC-2), and the canister built with
+//! `cargo build --target wasm32-unknown-unknown --release` (or
+//! `PROPOSAL_BACKEND_WASM` pointing at it).
+
+use candid::{decode_one, encode_args, encode_one, CandidType, Deserialize, Nat, Principal};
+use pocket_ic::{PocketIc, WasmResult};
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType)]
+#[allow(dead_code)]
+enum MetadataValue {
+    Text(String),
+}
+
+#[derive(CandidType)]
+struct ArchiveOptions {
+    num_blocks_to_archive: u64,
+    trigger_threshold: u64,
+    controller_id: Principal,
+}
+
+#[derive(CandidType)]
+struct FeatureFlags {
+    icrc2: bool,
+}
+
+#[derive(CandidType)]
+struct InitArgs {
+    minting_account: Account,
+    transfer_fee: Nat,
+    token_symbol: String,
+    token_name: String,
+    metadata: Vec<(String, MetadataValue)>,
+    initial_balances: Vec<(Account, Nat)>,
+    archive_options: ArchiveOptions,
+    feature_flags: Option<FeatureFlags>,
+}
+
+#[derive(CandidType)]
+enum LedgerArg {
+    Init(InitArgs),
+}
+
+#[derive(CandidType)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType)]
+struct ApproveArgs {
+    from_subaccount: Option<Vec<u8>>,
+    spender: Account,
+    amount: Nat,
+    expected_allowance: Option<Nat>,
+    expires_at: Option<u64>,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+/// The fields of the canister's `CreateProposal` this test sets; the others
+/// are optional and left out.
+#[derive(CandidType)]
+struct CreateProposal {
+    description: String,
+    open: bool,
+    ledger: Option<Principal>,
+}
+
+#[derive(CandidType)]
+enum Choice {
+    Approve,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum ProposalStatus {
+    Draft,
+    Open,
+    Revealing,
+    Tallying,
+    Closed,
+    Executed,
+    Cancelled,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum VoteError {
+    AlreadyVoted,
+    ProposalIsNotActive,
+    NoSuchProposal,
+    AccessRejected,
+    ProposalAlreadyExists,
+    ReasonTooLong,
+    DescriptionTooLong,
+    ProposalTooLarge,
+    VotingPeriodEnded,
+    InvalidVotingPeriod,
+    InvalidRules,
+    InvalidWeight,
+    InvalidOptions,
+    InvalidChoice,
+    InsufficientCredits { cost: u64, remaining: u64 },
+    InsufficientStake { requested: u64, available: u64 },
+    NoVotingPower,
+    LedgerCallFailed(String),
+    NotTokenWeighted,
+    TokensLocked,
+    NotAMember,
+    AlreadyAMember,
+    ProposalNotEditable,
+    InvalidJurySize,
+    NotAJuror,
+    RandomnessUnavailable(String),
+    CommitmentRequired,
+    NotASecretBallot,
+    InvalidCommitment,
+    NotCommitted,
+    CommitmentMismatch,
+    NotVoted,
+    VoteChangesDisabled,
+    TooManyBallotChanges,
+    InvalidDelegate,
+    DelegationCycle,
+    NotDelegating,
+    SnapshotInProgress,
+    InvalidTransition { from: ProposalStatus, to: ProposalStatus },
+    UpdateError(String),
+}
+
+#[derive(CandidType, Deserialize)]
+struct Tally {
+    approve: u64,
+}
+
+/// The fields of `ProposalView` this test reads.
+#[derive(CandidType, Deserialize)]
+struct ProposalView {
+    weighted: Option<Tally>,
+}
+
+fn wasm(variable: &str, default: Option<&str>) -> Vec<u8> {
+    let path: String = std::env::var(variable)
+        .ok()
+        .or_else(|| default.map(String::from))
+        .unwrap_or_else(|| panic!("{} is not set", variable));
+    std::fs::read(&path).unwrap_or_else(|e| panic!("cannot read {}: {}", path, e))
+}
+
+fn account(owner: Principal) -> Account {
+    Account {
+        owner,
+        subaccount: None,
+    }
+}
+
+fn update<R: for<'de> Deserialize<'de> + CandidType>(
+    pic: &PocketIc,
+    canister: Principal,
+    sender: Principal,
+    method: &str,
+    arg: Vec<u8>,
+) -> R {
+    match pic.update_call(canister, sender, method, arg).expect("call failed") {
+        WasmResult::Reply(bytes) => decode_one(&bytes).expect("cannot decode reply"),
+        WasmResult::Reject(message) => panic!("{} was rejected: {}", method, message),
+    }
+}
+
+fn weighted_approvals(pic: &PocketIc, backend: Principal, key: u64) -> u64 {
+    let reply = pic
+        .query_call(
+            backend,
+            Principal::anonymous(),
+            "get_proposal",
+            encode_one(key).unwrap(),
+        )
+        .expect("call failed");
+    let WasmResult::Reply(bytes) = reply else {
+        panic!("get_proposal was rejected");
+    };
+    let proposal: Option<ProposalView> = decode_one(&bytes).expect("cannot decode reply");
+    proposal
+        .and_then(|p| p.weighted)
+        .map(|t| t.approve)
+        .expect("tallies are shown")
+}
+
+fn balance(pic: &PocketIc, ledger: Principal, owner: Principal) -> u64 {
+    let reply = pic
+        .query_call(
+            ledger,
+            Principal::anonymous(),
+            "icrc1_balance_of",
+            encode_one(account(owner)).unwrap(),
+        )
+        .expect("call failed");
+    let WasmResult::Reply(bytes) = reply else {
+        panic!("icrc1_balance_of was rejected");
+    };
+    let balance: Nat = decode_one(&bytes).expect("cannot decode reply");
+    u64::try_from(balance.0).expect("balance fits in u64")
+}
+
+/// Approves the backend for `amount` tokens of `voter` and escrows them on
+/// proposal `key`.
+fn escrow(
+    pic: &PocketIc,
+    ledger: Principal,
+    backend: Principal,
+    voter: Principal,
+    key: u64,
+    amount: u64,
+) -> Result<(), VoteError> {
+    let approve = ApproveArgs {
+        from_subaccount: None,
+        spender: account(backend),
+        amount: Nat::from(amount),
+        expected_allowance: None,
+        expires_at: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let _: Result<Nat, candid::Reserved> = update(pic, ledger, voter, "icrc2_approve", encode_one(approve).unwrap());
+    update(pic, backend, voter, "escrow_tokens", encode_args((key, amount)).unwrap())
+}
+
+/// Installs the ledger with `balances` and the backend with `admin` as its
+/// first admin.
+fn setup(admin: Principal, balances: &[(Principal, u64)]) -> (PocketIc, Principal, Principal) {
+    let pic = PocketIc::new();
+
+    let ledger = pic.create_canister();
+    pic.add_cycles(ledger, 2_000_000_000_000);
+    let init = LedgerArg::Init(InitArgs {
+        minting_account: account(Principal::from_slice(&[0xee; 29])),
+        transfer_fee: Nat::from(0u64),
+        token_symbol: "GOV".to_string(),
+        token_name: "Governance".to_string(),
+        metadata: vec![],
+        initial_balances: balances
+            .iter()
+            .map(|(owner, amount)| (account(*owner), Nat::from(*amount)))
+            .collect(),
+        archive_options: ArchiveOptions {
+            num_blocks_to_archive: 1000,
+            trigger_threshold: 2000,
+            controller_id: Principal::anonymous(),
+        },
+        feature_flags: Some(FeatureFlags { icrc2: true }),
+    });
+    pic.install_canister(ledger, wasm("ICRC1_LEDGER_WASM", None), encode_one(init).unwrap(), None);
+
+    let backend = pic.create_canister_with_settings(Some(admin), None);
+    pic.add_cycles(backend, 2_000_000_000_000);
+    let default_wasm: String = format!(
+        "{}/../../target/wasm32-unknown-unknown/release/Proposal_backend.wasm",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    pic.install_canister(
+        backend,
+        wasm("PROPOSAL_BACKEND_WASM", Some(&default_wasm)),
+        encode_args(()).unwrap(),
+        Some(admin),
+    );
+
+    (pic, ledger, backend)
+}
+
+fn create_token_proposal(pic: &PocketIc, ledger: Principal, backend: Principal, owner: Principal) -> u64 {
+    let proposal = CreateProposal {
+        description: "Fund the grants programme".to_string(),
+        open: true,
+        ledger: Some(ledger),
+    };
+    let key: Result<u64, VoteError> = update(pic, backend, owner, "create_proposal", encode_one(proposal).unwrap());
+    key.expect("proposal is created")
+}
+
+#[test]
+fn votes_are_weighted_by_escrowed_tokens() {
+    let alice = Principal::from_slice(&[1; 29]);
+    let bob = Principal::from_slice(&[2; 29]);
+    let (pic, ledger, backend) = setup(alice, &[(alice, 100)]);
+    let key: u64 = create_token_proposal(&pic, ledger, backend, alice);
+
+    assert_eq!(escrow(&pic, ledger, backend, alice, key, 100), Ok(()));
+    assert_eq!(balance(&pic, ledger, alice), 0);
+
+    let vote = encode_args((key, Choice::Approve, None::<String>)).unwrap();
+    let voted: Result<(), VoteError> = update(&pic, backend, alice, "vote", vote.clone());
+    assert_eq!(voted, Ok(()));
+    assert_eq!(weighted_approvals(&pic, backend, key), 100);
+
+    let voted: Result<(), VoteError> = update(&pic, backend, bob, "vote", vote);
+    assert_eq!(voted, Err(VoteError::NoVotingPower));
+}
+
+/// Tokens that voted stay in escrow until voting ends, so they cannot be
+/// moved to another account and vote again from there.
+#[test]
+fn escrowed_tokens_cannot_vote_again() {
+    let alice = Principal::from_slice(&[1; 29]);
+    let bob = Principal::from_slice(&[2; 29]);
+    let (pic, ledger, backend) = setup(alice, &[(alice, 100)]);
+    let key: u64 = create_token_proposal(&pic, ledger, backend, alice);
+
+    assert_eq!(escrow(&pic, ledger, backend, alice, key, 100), Ok(()));
+    let vote = encode_args((key, Choice::Approve, None::<String>)).unwrap();
+    let voted: Result<(), VoteError> = update(&pic, backend, alice, "vote", vote.clone());
+    assert_eq!(voted, Ok(()));
+
+    let transfer = TransferArg {
+        from_subaccount: None,
+        to: account(bob),
+        amount: Nat::from(60u64),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let transferred: Result<Nat, candid::Reserved> =
+        update(&pic, ledger, alice, "icrc1_transfer", encode_one(transfer).unwrap());
+    assert!(transferred.is_err());
+
+    assert!(matches!(
+        escrow(&pic, ledger, backend, bob, key, 60),
+        Err(VoteError::LedgerCallFailed(_))
+    ));
+    let voted: Result<(), VoteError> = update(&pic, backend, bob, "vote", vote);
+    assert_eq!(voted, Err(VoteError::NoVotingPower));
+    assert_eq!(weighted_approvals(&pic, backend, key), 100);
+
+    let withdrawn: Result<u64, VoteError> = update(&pic, backend, alice, "withdraw_tokens", encode_one(key).unwrap());
+    assert_eq!(withdrawn, Err(VoteError::TokensLocked));
+
+    let ended: Result<(), VoteError> = update(&pic, backend, alice, "end_proposal", encode_one(key).unwrap());
+    assert_eq!(ended, Ok(()));
+    let withdrawn: Result<u64, VoteError> = update(&pic, backend, alice, "withdraw_tokens", encode_one(key).unwrap());
+    assert_eq!(withdrawn, Ok(100));
+    assert_eq!(balance(&pic, ledger, alice), 100);
+}